@@ -0,0 +1,337 @@
+use std::f32::consts::PI;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chrono::Utc;
+use serde::Deserialize;
+
+use crate::influx::{Log, Measurement};
+use crate::mixer::Axis;
+use crate::schedule::Gains;
+
+/// How the ultimate gain and period are turned into PID gains
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TuningRule {
+    ZieglerNichols,
+    TyreusLuyben,
+    NoOvershoot,
+    PessenIntegral,
+}
+
+impl TuningRule {
+    /// Gains of the parallel PID from the ultimate gain and period
+    fn gains(self, ku: f32, tu: f32) -> Gains {
+        // proportional gain, integral time, derivative time
+        let (kp, ti, td) = match self {
+            TuningRule::ZieglerNichols => (0.6 * ku, tu / 2.0, tu / 8.0),
+            TuningRule::TyreusLuyben => (ku / 2.2, 2.2 * tu, tu / 6.3),
+            TuningRule::NoOvershoot => (0.2 * ku, tu / 2.0, tu / 3.0),
+            TuningRule::PessenIntegral => (0.7 * ku, 0.4 * tu, 0.15 * tu),
+        };
+        Gains {
+            p: kp,
+            i: kp / ti,
+            d: kp * td,
+        }
+    }
+}
+
+/// Relay settings of one axis
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct AxisExperiment {
+    axis: Axis,
+    /// relay output, in units of the PID output
+    amplitude: f32,
+    /// hysteresis of the relay, in units of the measurement
+    hysteresis: f32,
+}
+
+#[derive(Deserialize)]
+struct AutotuneSettings {
+    cycles: usize,
+    timeout_s: f32,
+    rule: TuningRule,
+    axes: Vec<AxisExperiment>,
+    results_path: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "AutotuneSettings")]
+pub struct AutotuneConfig {
+    /// oscillation periods averaged after the first one, at least 1
+    cycles: usize,
+    /// an axis is given up if it does not oscillate in time
+    timeout_s: f32,
+    rule: TuningRule,
+    /// tuned one after the other
+    axes: Vec<AxisExperiment>,
+    /// the proposed gains are appended to this file
+    results_path: String,
+}
+
+impl TryFrom<AutotuneSettings> for AutotuneConfig {
+    type Error = String;
+
+    fn try_from(settings: AutotuneSettings) -> Result<Self, Self::Error> {
+        if settings.cycles == 0 {
+            return Err("autotune: cycles must be at least 1".to_string());
+        }
+        Ok(Self {
+            cycles: settings.cycles,
+            timeout_s: settings.timeout_s,
+            rule: settings.rule,
+            axes: settings.axes,
+            results_path: settings.results_path,
+        })
+    }
+}
+
+/// Relay feedback experiment (Åström–Hägglund) on one axis
+#[derive(Debug)]
+struct Relay {
+    amplitude: f32,
+    hysteresis: f32,
+    output: f32,
+    time: f32,
+    last_rise: Option<f32>,
+    max: f32,
+    min: f32,
+    periods: Vec<f32>,
+    amplitudes: Vec<f32>,
+}
+
+impl Relay {
+    fn new(amplitude: f32, hysteresis: f32) -> Self {
+        Self {
+            amplitude,
+            hysteresis,
+            output: 0.0,
+            time: 0.0,
+            last_rise: None,
+            max: f32::MIN,
+            min: f32::MAX,
+            periods: Vec::new(),
+            amplitudes: Vec::new(),
+        }
+    }
+
+    /// Returns the relay output for the error of the axis
+    fn update(&mut self, error: f32, dt: f32) -> f32 {
+        self.time += dt;
+        self.max = self.max.max(error);
+        self.min = self.min.min(error);
+
+        if self.output == 0.0 {
+            self.output = self.amplitude.copysign(error);
+        } else if self.output > 0.0 && error < -self.hysteresis {
+            self.output = -self.amplitude;
+        } else if self.output < 0.0 && error > self.hysteresis {
+            self.output = self.amplitude;
+            // a full period ends with every rising switch
+            if let Some(last_rise) = self.last_rise {
+                self.periods.push(self.time - last_rise);
+                self.amplitudes.push((self.max - self.min) / 2.0);
+            }
+            self.last_rise = Some(self.time);
+            self.max = error;
+            self.min = error;
+        }
+        self.output
+    }
+
+    /// Ultimate gain and period, once enough periods have been seen. The first one is skipped
+    fn result(&self, cycles: usize) -> Option<(f32, f32)> {
+        if self.periods.len() <= cycles {
+            return None;
+        }
+        let tu = self.periods[1..].iter().sum::<f32>() / cycles as f32;
+        let a = self.amplitudes[1..].iter().sum::<f32>() / cycles as f32;
+        // describing function of a relay with hysteresis
+        let a_effective = (a * a - self.hysteresis * self.hysteresis).max(0.0).sqrt();
+        let a_effective = if a_effective > 0.0 { a_effective } else { a };
+        Some((4.0 * self.amplitude / (PI * a_effective), tu))
+    }
+}
+
+/// Progress and result of the autotune, for telemetry
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AutotuneStatus {
+    pub axis: Option<Axis>,
+    pub ku: f32,
+    pub tu: f32,
+    pub gains: Gains,
+}
+
+impl Log for AutotuneStatus {
+    fn measurements(&self) -> Vec<Measurement> {
+        vec![
+            Measurement {
+                name: "active",
+                value: self.axis.is_some() as u8 as f32,
+            },
+            Measurement {
+                name: "axis",
+                value: self.axis.map_or(-1.0, |axis| axis as usize as f32),
+            },
+            Measurement {
+                name: "ku",
+                value: self.ku,
+            },
+            Measurement {
+                name: "tu",
+                value: self.tu,
+            },
+            Measurement {
+                name: "p",
+                value: self.gains.p,
+            },
+            Measurement {
+                name: "i",
+                value: self.gains.i,
+            },
+            Measurement {
+                name: "d",
+                value: self.gains.d,
+            },
+        ]
+    }
+}
+
+/// Runs the relay experiment on the configured axes one at a time
+#[derive(Debug)]
+pub struct Autotune {
+    remaining: Vec<AxisExperiment>,
+    relay: Option<(Axis, Relay)>,
+    /// name of the loop driving each axis, the gains are proposed for it
+    loops: [&'static str; 4],
+    /// lines for the results file, written outside the control loop
+    results: Sender<String>,
+    status: Arc<Mutex<AutotuneStatus>>,
+}
+
+impl Autotune {
+    pub fn new(
+        config: &AutotuneConfig,
+        loops: [&'static str; 4],
+        status: Arc<Mutex<AutotuneStatus>>,
+    ) -> Self {
+        let mut remaining = config.axes.clone();
+        remaining.reverse();
+        Self {
+            remaining,
+            relay: None,
+            loops,
+            results: spawn_writer(config.results_path.clone()),
+            status,
+        }
+    }
+
+    /// The axis under test, the next one starts once the last one finished. None when all are done
+    pub fn axis(&mut self) -> Option<Axis> {
+        if self.relay.is_none() {
+            let experiment = self.remaining.pop()?;
+            println!("[Autotune] {}", self.loops[experiment.axis as usize]);
+            self.relay = Some((
+                experiment.axis,
+                Relay::new(experiment.amplitude, experiment.hysteresis),
+            ));
+            self.status.lock().unwrap().axis = Some(experiment.axis);
+        }
+        self.relay.as_ref().map(|(axis, _)| *axis)
+    }
+
+    /// Takes the error of the loop under test, the one whose output the relay replaces.
+    /// Returns the relay output and whether the axis just finished, then its PID should be reset.
+    /// The proposed gains are appended to the results file
+    pub fn update(&mut self, config: &AutotuneConfig, error: f32, dt: f32) -> Option<(f32, bool)> {
+        let (axis, relay) = self.relay.as_mut()?;
+        let axis = *axis;
+        let output = relay.update(error, dt);
+
+        if let Some((ku, tu)) = relay.result(config.cycles) {
+            let gains = config.rule.gains(ku, tu);
+            *self.status.lock().unwrap() = AutotuneStatus {
+                axis: None,
+                ku,
+                tu,
+                gains,
+            };
+            let line = format!(
+                "# {} {:?}, ku: {}, tu: {}\n{}: {{ p: {}, i: {}, d: {} }}\n",
+                Utc::now().to_rfc3339(),
+                config.rule,
+                ku,
+                tu,
+                self.loops[axis as usize],
+                gains.p,
+                gains.i,
+                gains.d
+            );
+            // the writer is gone only if it panicked, the status still has the result
+            let _ = self.results.send(line);
+            self.relay = None;
+            return Some((output, true));
+        }
+        if relay.time > config.timeout_s {
+            println!(
+                "[Autotune] {} did not oscillate, giving up",
+                self.loops[axis as usize]
+            );
+            self.status.lock().unwrap().axis = None;
+            self.relay = None;
+            return Some((output, true));
+        }
+        Some((output, false))
+    }
+}
+
+/// Appends the lines sent to it to the results file, until the sender is dropped
+fn spawn_writer(path: String) -> Sender<String> {
+    let (sender, receiver) = channel::<String>();
+    thread::spawn(move || {
+        for line in receiver {
+            print!("[Autotune] {}", line);
+            let file = OpenOptions::new().create(true).append(true).open(&path);
+            if let Err(e) = file.and_then(|mut file| file.write_all(line.as_bytes())) {
+                eprintln!("[Autotune] could not write {}: {:?}", path, e);
+            }
+        }
+    });
+    sender
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Relay, TuningRule};
+
+    #[test]
+    fn test_relay_estimate() {
+        // first order lag with dead time, oscillates under relay feedback
+        let dt = 0.001;
+        let mut relay = Relay::new(1.0, 0.0);
+        let mut y = 0.0;
+        let mut delayed = vec![0.0; 100];
+        let mut result = None;
+        for k in 0..20000 {
+            let u = relay.update(-y, dt);
+            delayed[k % 100] = u;
+            let u_delayed = delayed[(k + 1) % 100];
+            y += dt * (u_delayed - y);
+            result = relay.result(4);
+            if result.is_some() {
+                break;
+            }
+        }
+        let (ku, tu) = result.unwrap();
+        // exact for this plant: tu = 4 * dead time ~ 0.4 s, ku ~ 4 / (pi * 0.1)
+        assert!((tu - 0.4).abs() < 0.05);
+        assert!(ku > 10.0 && ku < 15.0);
+
+        let gains = TuningRule::ZieglerNichols.gains(ku, tu);
+        assert!((gains.p - 0.6 * ku).abs() < 1e-5);
+    }
+}
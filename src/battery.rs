@@ -0,0 +1,62 @@
+use serde::Deserialize;
+use std::fs;
+use std::thread::{self, sleep};
+use std::time::Duration;
+
+use crate::bus::Bus;
+
+/// Battery voltage from an ADC with a Linux IIO driver, e.g. an ADS1015 behind a voltage divider
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BatteryConfig {
+    pub enabled: bool,
+    /// raw value of the ADC channel
+    pub path: String,
+    /// volts per raw count, including the divider
+    pub scale: f32,
+    pub interval_ms: u64,
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: String::from("/sys/bus/iio/devices/iio:device0/in_voltage0_raw"),
+            scale: 0.01,
+            interval_ms: 500,
+        }
+    }
+}
+
+/// Volts from the raw value the driver reports, None if it is not a number
+fn voltage(raw: &str, scale: f32) -> Option<f32> {
+    raw.trim().parse::<f32>().ok().map(|raw| raw * scale)
+}
+
+/// Publishes the battery voltage as "battery_voltage", it stays at the last value while the ADC cannot be read
+pub fn spawn_battery(config: &BatteryConfig, bus: &Bus) {
+    let battery = bus.latest::<f32>("battery_voltage");
+    let config = config.clone();
+
+    thread::spawn(move || loop {
+        match fs::read_to_string(&config.path) {
+            Ok(raw) => match voltage(&raw, config.scale) {
+                Some(volts) => *battery.lock().unwrap() = volts,
+                None => eprintln!("[Battery] unexpected value {:?}", raw),
+            },
+            Err(e) => eprintln!("[Battery] cannot read {}: {}", config.path, e),
+        }
+        sleep(Duration::from_millis(config.interval_ms));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::voltage;
+
+    #[test]
+    fn test_voltage() {
+        assert_eq!(Some(12.5), voltage("1250\n", 0.01));
+        assert_eq!(None, voltage("", 0.01));
+    }
+}
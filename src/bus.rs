@@ -0,0 +1,134 @@
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::influx::{influx_log, Log};
+
+/// Queued topic, every subscriber receives every value published after it subscribed
+pub struct Queue<T> {
+    subscribers: Arc<Mutex<Vec<Sender<T>>>>,
+}
+
+impl<T> Clone for Queue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            subscribers: self.subscribers.clone(),
+        }
+    }
+}
+
+impl<T: Clone> Queue<T> {
+    pub fn publish(&self, value: T) {
+        // subscribers that went away are dropped
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(value.clone()).is_ok());
+    }
+
+    pub fn subscribe(&self) -> Receiver<T> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+/// Named topics shared between the sensor threads, the control loop and the loggers.
+/// A topic is created by whoever asks for it first and keeps its type,
+/// asking for an existing topic with another type is a bug and panics.
+/// Topics asked for as logged are sent to influx under their name
+#[derive(Clone)]
+pub struct Bus {
+    topics: Arc<Mutex<HashMap<String, Box<dyn Any + Send>>>>,
+    logged: Arc<Mutex<HashSet<String>>>,
+    /// None disables logging, e.g. in the sim
+    logging_interval: Option<Duration>,
+}
+
+impl Bus {
+    pub fn new(logging_interval: Option<Duration>) -> Self {
+        Self {
+            topics: Arc::new(Mutex::new(HashMap::new())),
+            logged: Arc::new(Mutex::new(HashSet::new())),
+            logging_interval,
+        }
+    }
+
+    fn topic<H: Clone + Send + 'static>(&self, name: &str, create: impl FnOnce() -> H) -> H {
+        let mut topics = self.topics.lock().unwrap();
+        let topic = topics
+            .entry(name.to_string())
+            .or_insert_with(|| Box::new(create()));
+        match topic.downcast_ref::<H>() {
+            Some(handle) => handle.clone(),
+            None => panic!("[Bus] topic {} has another type", name),
+        }
+    }
+
+    /// Latest value topic
+    pub fn latest<T: Default + Send + 'static>(&self, name: &str) -> Arc<Mutex<T>> {
+        self.topic(name, || Arc::new(Mutex::new(T::default())))
+    }
+
+    /// Latest value topic that is logged
+    pub fn logged<T: Log + Default>(&self, name: &str) -> Arc<Mutex<T>> {
+        let shared = self.latest::<T>(name);
+        self.log(name, shared.clone());
+        shared
+    }
+
+    /// Publishes a value that is already shared elsewhere, e.g. the telemetry of a controller, and logs it
+    pub fn attach<T: Log + ?Sized>(&self, name: &str, shared: Arc<Mutex<T>>) {
+        self.topic(name, || shared.clone());
+        self.log(name, shared);
+    }
+
+    pub fn queue<T: Send + 'static>(&self, name: &str) -> Queue<T> {
+        self.topic(name, || Queue {
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    fn log<T: Log + ?Sized>(&self, name: &str, shared: Arc<Mutex<T>>) {
+        let Some(interval) = self.logging_interval else {
+            return;
+        };
+        if self.logged.lock().unwrap().insert(name.to_string()) {
+            influx_log(shared, name.to_string(), interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bus;
+
+    #[test]
+    fn test_latest() {
+        let bus = Bus::new(None);
+        *bus.latest::<f32>("altitude").lock().unwrap() = 0.4;
+        assert_eq!(0.4, *bus.clone().latest::<f32>("altitude").lock().unwrap());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_type_mismatch() {
+        let bus = Bus::new(None);
+        bus.latest::<f32>("altitude");
+        bus.latest::<u32>("altitude");
+    }
+
+    #[test]
+    fn test_queue() {
+        let bus = Bus::new(None);
+        let first = bus.queue::<u8>("events").subscribe();
+        bus.queue::<u8>("events").publish(1);
+        let second = bus.queue::<u8>("events").subscribe();
+        bus.queue::<u8>("events").publish(2);
+
+        assert_eq!(vec![1, 2], first.try_iter().collect::<Vec<_>>());
+        assert_eq!(vec![2], second.try_iter().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,190 @@
+use serde::{Deserialize, Serialize};
+
+/// Attitude of the IMU when the boat is level, subtracted from roll and pitch in degrees
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+pub struct LevelOffset {
+    pub roll: f32,
+    pub pitch: f32,
+}
+
+impl LevelOffset {
+    /// None if there is no readable offset file
+    pub fn load(path: &str) -> Option<Self> {
+        let yaml = std::fs::read_to_string(path).ok()?;
+        serde_yaml::from_str(&yaml).ok()
+    }
+
+    pub fn save(&self, path: &str) {
+        let result = serde_yaml::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|yaml| std::fs::write(path, yaml).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("[Calibration] could not write {}: {}", path, e);
+        }
+    }
+}
+
+/// A calibration config as written, checked before it becomes a `CalibrationConfig`
+#[derive(Deserialize)]
+#[serde(default)]
+struct CalibrationSettings {
+    window_s: f32,
+    tolerance: f32,
+    offset_path: String,
+}
+
+impl Default for CalibrationSettings {
+    fn default() -> Self {
+        let CalibrationConfig {
+            window_s,
+            tolerance,
+            offset_path,
+        } = CalibrationConfig::default();
+        Self {
+            window_s,
+            tolerance,
+            offset_path,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "CalibrationSettings")]
+pub struct CalibrationConfig {
+    /// the boat has to be still for this long, in seconds, above 0
+    pub window_s: f32,
+    /// maximum spread of roll and pitch within the window, in degrees, at least 0
+    pub tolerance: f32,
+    /// the offset is loaded from here on startup, without it the IMU calibrates itself
+    pub offset_path: String,
+}
+
+impl Default for CalibrationConfig {
+    fn default() -> Self {
+        Self {
+            window_s: 3.0,
+            tolerance: 0.5,
+            offset_path: String::from("imu_offset.yaml"),
+        }
+    }
+}
+
+impl TryFrom<CalibrationSettings> for CalibrationConfig {
+    type Error = String;
+
+    fn try_from(settings: CalibrationSettings) -> Result<Self, Self::Error> {
+        if !settings.window_s.is_finite() || settings.window_s <= 0.0 {
+            return Err("calibration: window_s must be above 0".to_string());
+        }
+        if settings.tolerance.is_nan() || settings.tolerance < 0.0 {
+            return Err("calibration: tolerance must be at least 0".to_string());
+        }
+        Ok(Self {
+            window_s: settings.window_s,
+            tolerance: settings.tolerance,
+            offset_path: settings.offset_path,
+        })
+    }
+}
+
+/// Averages roll and pitch while the boat is still.
+/// If it moves, the window starts over
+#[derive(Debug)]
+pub struct LevelCalibration {
+    window_s: f32,
+    tolerance: f32,
+    elapsed: f32,
+    samples: u32,
+    sum: LevelOffset,
+    min: LevelOffset,
+    max: LevelOffset,
+}
+
+impl LevelCalibration {
+    pub fn new(config: &CalibrationConfig) -> Self {
+        let mut calibration = Self {
+            window_s: config.window_s,
+            tolerance: config.tolerance,
+            elapsed: 0.0,
+            samples: 0,
+            sum: LevelOffset::default(),
+            min: LevelOffset::default(),
+            max: LevelOffset::default(),
+        };
+        calibration.restart();
+        calibration
+    }
+
+    fn restart(&mut self) {
+        self.elapsed = 0.0;
+        self.samples = 0;
+        self.sum = LevelOffset::default();
+        self.min = LevelOffset {
+            roll: f32::MAX,
+            pitch: f32::MAX,
+        };
+        self.max = LevelOffset {
+            roll: f32::MIN,
+            pitch: f32::MIN,
+        };
+    }
+
+    /// Takes an uncorrected attitude sample. Returns the offset once the boat was still for the whole window
+    pub fn push(&mut self, roll: f32, pitch: f32, dt: f32) -> Option<LevelOffset> {
+        self.min.roll = self.min.roll.min(roll);
+        self.min.pitch = self.min.pitch.min(pitch);
+        self.max.roll = self.max.roll.max(roll);
+        self.max.pitch = self.max.pitch.max(pitch);
+        if self.max.roll - self.min.roll > self.tolerance
+            || self.max.pitch - self.min.pitch > self.tolerance
+        {
+            // the window starts over with this sample
+            self.restart();
+            self.min = LevelOffset { roll, pitch };
+            self.max = self.min;
+        }
+
+        self.sum.roll += roll;
+        self.sum.pitch += pitch;
+        self.samples += 1;
+        self.elapsed += dt;
+        if self.elapsed < self.window_s {
+            return None;
+        }
+        Some(LevelOffset {
+            roll: self.sum.roll / self.samples as f32,
+            pitch: self.sum.pitch / self.samples as f32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CalibrationConfig, LevelCalibration};
+
+    #[test]
+    fn test_stillness() {
+        let mut calibration = LevelCalibration::new(&CalibrationConfig::default());
+        // rocking at the dock
+        for k in 0..100 {
+            let roll = 2.0 * (k as f32 * 0.1).sin();
+            assert_eq!(None, calibration.push(roll, 1.0, 0.02));
+        }
+        // still from now on, the window starts over
+        let mut offset = None;
+        let mut samples = 0;
+        while offset.is_none() {
+            offset = calibration.push(1.5 + 0.1 * (samples % 2) as f32, 1.0, 0.02);
+            samples += 1;
+        }
+        assert!(samples >= 150);
+        let offset = offset.unwrap();
+        assert!((offset.roll - 1.55).abs() < 0.01);
+        assert_eq!(1.0, offset.pitch);
+
+        assert!(serde_yaml::from_str::<CalibrationConfig>("{ tolerance: -0.1 }").is_err());
+        assert!(serde_yaml::from_str::<CalibrationConfig>("{ window_s: 0.0 }").is_err());
+        let config: CalibrationConfig = serde_yaml::from_str("{ tolerance: 0.0 }").unwrap();
+        assert_eq!(3.0, config.window_s);
+    }
+}
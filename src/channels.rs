@@ -0,0 +1,310 @@
+use serde::Deserialize;
+
+use crate::control::State;
+use crate::flight_mode::FlightMode;
+use crate::receiver::Inputs;
+
+/// Switches are on above this
+const SWITCH_THRESHOLD: f32 = 0.6;
+
+/// One RC channel and its calibration, pulse widths in µs.
+/// The calibration needs min < center < max and a deadband from 0 to below 1
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "ChannelConfig")]
+pub struct Channel {
+    /// index in the frame, starting at 0
+    pub channel: usize,
+    pub min: f32,
+    pub center: f32,
+    pub max: f32,
+    pub reversed: bool,
+    /// fraction of the stick travel around center that reads as 0
+    pub deadband: f32,
+    /// 0 is linear, 1 is fully cubic
+    pub expo: f32,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            channel: 0,
+            min: 1000.0,
+            center: 1500.0,
+            max: 2000.0,
+            reversed: false,
+            deadband: 0.0,
+            expo: 0.0,
+        }
+    }
+}
+
+/// A channel as written in the config, checked before it becomes a `Channel`
+#[derive(Deserialize)]
+#[serde(default)]
+struct ChannelConfig {
+    channel: usize,
+    min: f32,
+    center: f32,
+    max: f32,
+    reversed: bool,
+    deadband: f32,
+    expo: f32,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        let Channel {
+            channel,
+            min,
+            center,
+            max,
+            reversed,
+            deadband,
+            expo,
+        } = Channel::default();
+        Self {
+            channel,
+            min,
+            center,
+            max,
+            reversed,
+            deadband,
+            expo,
+        }
+    }
+}
+
+impl TryFrom<ChannelConfig> for Channel {
+    type Error = String;
+
+    fn try_from(config: ChannelConfig) -> Result<Self, Self::Error> {
+        if !(config.min < config.center && config.center < config.max) {
+            return Err(format!(
+                "channels: channel {} needs min < center < max",
+                config.channel
+            ));
+        }
+        if !(0.0..1.0).contains(&config.deadband) {
+            return Err(format!(
+                "channels: deadband of channel {} must be at least 0 and below 1",
+                config.channel
+            ));
+        }
+        Ok(Self {
+            channel: config.channel,
+            min: config.min,
+            center: config.center,
+            max: config.max,
+            reversed: config.reversed,
+            deadband: config.deadband,
+            expo: config.expo,
+        })
+    }
+}
+
+impl Channel {
+    fn on(channel: usize) -> Self {
+        Self {
+            channel,
+            ..Self::default()
+        }
+    }
+
+    /// Maps the raw channel values to -1..1, a missing channel reads as center
+    pub fn value(&self, raw: &[u16]) -> f32 {
+        let Some(&pulse) = raw.get(self.channel) else {
+            return 0.0;
+        };
+        let pulse = pulse as f32;
+        let mut value = if pulse >= self.center {
+            (pulse - self.center) / (self.max - self.center)
+        } else {
+            (pulse - self.center) / (self.center - self.min)
+        }
+        .clamp(-1.0, 1.0);
+        if self.reversed {
+            value = -value;
+        }
+        value = if value.abs() <= self.deadband {
+            0.0
+        } else {
+            value.signum() * (value.abs() - self.deadband) / (1.0 - self.deadband)
+        };
+        value * (1.0 - self.expo) + value.powi(3) * self.expo
+    }
+
+    pub fn switch(&self, raw: &[u16]) -> bool {
+        self.value(raw) > SWITCH_THRESHOLD
+    }
+}
+
+/// Which channel drives which function of the boat
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Channels {
+    pub roll: Channel,
+    pub pitch: Channel,
+    pub yaw: Channel,
+    pub altitude: Channel,
+    pub enable: Channel,
+    pub autotune: Channel,
+    pub manual: Channel,
+    pub calibrate: Channel,
+    /// multi position mode switch, replaces the enable, manual and autotune switches
+    pub mode: Option<Channel>,
+    /// mode of each position of the mode switch, from low to high
+    pub modes: Vec<FlightMode>,
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Self {
+            roll: Channel::on(0),
+            pitch: Channel::on(1),
+            yaw: Channel::on(3),
+            altitude: Channel::on(6),
+            enable: Channel::on(5),
+            autotune: Channel::on(7),
+            manual: Channel::on(4),
+            calibrate: Channel::on(8),
+            mode: None,
+            modes: vec![
+                FlightMode::Off,
+                FlightMode::Stabilise,
+                FlightMode::AltitudeHold,
+            ],
+        }
+    }
+}
+
+impl Channels {
+    fn mode(&self, raw: &[u16]) -> FlightMode {
+        let Some(channel) = &self.mode else {
+            return if self.manual.switch(raw) {
+                FlightMode::Manual
+            } else if !self.enable.switch(raw) {
+                FlightMode::Off
+            } else if self.autotune.switch(raw) {
+                FlightMode::Autotune
+            } else {
+                FlightMode::AltitudeHold
+            };
+        };
+        let Some(last) = self.modes.len().checked_sub(1) else {
+            return FlightMode::Off;
+        };
+        let position = ((channel.value(raw) + 1.0) / 2.0 * last as f32).round() as usize;
+        self.modes[position.min(last)]
+    }
+
+    /// Inputs from the raw channel values, the sticks move the setpoint around the default by the sensitivity
+    pub fn inputs(&self, raw: &[u16], sensitivity: State, default_setpoint: State) -> Inputs {
+        let sticks = [
+            self.roll.value(raw),
+            self.pitch.value(raw),
+            self.yaw.value(raw),
+            self.altitude.value(raw),
+        ];
+        let relative_setpoint = State {
+            roll: sticks[0] * sensitivity.roll,
+            pitch: sticks[1] * sensitivity.pitch,
+            yaw_rate: sticks[2] * sensitivity.yaw_rate,
+            altitude: sticks[3] * sensitivity.altitude,
+            ..State::default()
+        };
+        Inputs {
+            setpoint: default_setpoint + relative_setpoint,
+            mode: self.mode(raw),
+            sticks,
+            calibrate: self.calibrate.switch(raw),
+            received: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Channel, Channels};
+    use crate::control::State;
+    use crate::flight_mode::FlightMode;
+
+    #[test]
+    fn test_channel() {
+        let channel = Channel {
+            min: 1100.0,
+            center: 1520.0,
+            max: 1900.0,
+            ..Channel::default()
+        };
+        assert_eq!(0.0, channel.value(&[1520]));
+        assert_eq!(1.0, channel.value(&[1900]));
+        assert_eq!(-1.0, channel.value(&[1100]));
+        assert_eq!(-1.0, channel.value(&[900]));
+        assert_eq!(0.0, Channel::on(20).value(&[1900]));
+
+        let channel = Channel {
+            reversed: true,
+            deadband: 0.1,
+            expo: 0.5,
+            ..Channel::default()
+        };
+        assert_eq!(0.0, channel.value(&[1540]));
+        assert_eq!(-1.0, channel.value(&[2000]));
+        // half of the travel outside the deadband
+        let value = channel.value(&[1225]);
+        assert!((value - (0.5 * 0.5 + 0.125 * 0.5)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_calibration() {
+        let channel: Channel = serde_yaml::from_str("{ channel: 2, center: 1520 }").unwrap();
+        assert_eq!(2, channel.channel);
+        assert_eq!(1520.0, channel.center);
+        assert_eq!(1000.0, channel.min);
+
+        assert!(serde_yaml::from_str::<Channel>("{ min: 1500 }").is_err());
+        assert!(serde_yaml::from_str::<Channel>("{ max: 900 }").is_err());
+        assert!(serde_yaml::from_str::<Channel>("{ deadband: 1.0 }").is_err());
+        assert!(serde_yaml::from_str::<Channel>("{ deadband: -0.1 }").is_err());
+    }
+
+    #[test]
+    fn test_default_mapping() {
+        let mut raw = [1500u16; 14];
+        raw[0] = 2000;
+        raw[5] = 2000;
+        let sensitivity = State {
+            roll: 10.0,
+            ..State::default()
+        };
+        let inputs = Channels::default().inputs(&raw, sensitivity, State::default());
+        assert_eq!(10.0, inputs.setpoint.roll);
+        assert_eq!([1.0, 0.0, 0.0, 0.0], inputs.sticks);
+        assert_eq!(FlightMode::AltitudeHold, inputs.mode);
+
+        // 3 position switch in the middle
+        let channels = Channels {
+            mode: Some(Channel::on(9)),
+            ..Channels::default()
+        };
+        raw[9] = 1520;
+        let inputs = channels.inputs(&raw, sensitivity, State::default());
+        assert_eq!(FlightMode::Stabilise, inputs.mode);
+    }
+
+    #[test]
+    fn test_config_modes() {
+        let config: serde_yaml::Value =
+            serde_yaml::from_str(include_str!("../config.yaml")).unwrap();
+        let channels: Channels =
+            serde_yaml::from_value(config["receiver"]["channels"].clone()).unwrap();
+        assert_eq!(
+            vec![
+                FlightMode::Off,
+                FlightMode::Manual,
+                FlightMode::AltitudeHold
+            ],
+            channels.modes
+        );
+    }
+}
@@ -1,40 +1,148 @@
+use crate::autotune::{Autotune, AutotuneConfig, AutotuneStatus};
 use crate::influx::{Log, Measurement};
+use crate::lqr::LqrController;
+use crate::mixer::{mix, ActuatorLimits, Axis, AxisPriority};
+use crate::schedule::{ActiveGains, GainSchedule, Gains};
+use crate::staleness::SampleTimes;
+use nalgebra::{Matrix4, Vector4};
 use serde::Deserialize;
 use std::{
+    f32::consts::PI,
     ops::Add,
     sync::{Arc, Mutex},
 };
 
-#[derive(Deserialize, Debug)]
+/// Seconds the output of the altitude loop fades out over once the altitude hold is turned off
+pub const ALTITUDE_FADE_S: f32 = 1.0;
+
+#[derive(Deserialize, Debug, Default)]
 struct Pid {
     p: f32,
     i: f32,
     d: f32,
     i_limit: f32,
+    /// differentiate the measurement instead of the error to avoid kicks on setpoint changes
+    #[serde(default)]
+    d_on_measurement: bool,
+    /// cutoff frequency of the first order low pass on the derivative, unfiltered if not set
+    #[serde(default)]
+    d_cutoff_hz: Option<f32>,
+    /// replaces p, i and d depending on speed or altitude
+    #[serde(default)]
+    schedule: Option<GainSchedule>,
+    #[serde(skip)]
+    scheduled: Option<Gains>,
+    /// time constant of the back-calculation anti windup in seconds, defaults to p / i
+    #[serde(default)]
+    tracking_time: Option<f32>,
     #[serde(default)]
     i_term: f32,
+    /// output before saturation
+    #[serde(default)]
+    output: f32,
     #[serde(default)]
     last_error: f32,
+    #[serde(default)]
+    last_measurement: f32,
+    #[serde(default)]
+    derivative: f32,
+    #[serde(default)]
+    initialized: bool,
 }
 
 impl Pid {
     fn update(&mut self, setpoint: f32, measurement: f32, dt: f32) -> f32 {
+        let gains = self.gains();
         let error = setpoint - measurement;
-        let p = error * self.p;
+        let p = error * gains.p;
 
         self.i_term = self.i_term + dt * error;
         // anti windup
         self.i_term = self.i_term.clamp(-self.i_limit, self.i_limit);
-        let i = self.i_term * self.i;
+        let i = self.i_term * gains.i;
+
+        let d = self.update_derivative(error, measurement, dt) * gains.d;
 
-        let derivative = (self.last_error - error) / dt;
-        let d = derivative * self.d;
+        self.output = p + i + d;
+        self.output.clamp(-1.0, 1.0)
+    }
+
+    /// Back-calculation anti windup: moves the integrator towards the output that could actually be achieved
+    fn back_calculate(&mut self, achieved: f32, dt: f32) {
+        let gains = self.gains();
+        if gains.i == 0.0 {
+            return;
+        }
+        let tracking_time = match self.tracking_time {
+            Some(tracking_time) => tracking_time,
+            None if gains.p > 0.0 => gains.p / gains.i,
+            None => return,
+        };
+        let correction = (dt / tracking_time).min(1.0) * (achieved - self.output) / gains.i;
+        self.i_term = (self.i_term + correction).clamp(-self.i_limit, self.i_limit);
+    }
+
+    /// The scheduled gains, or the fixed ones if there is no schedule
+    fn gains(&self) -> Gains {
+        self.scheduled.unwrap_or(Gains {
+            p: self.p,
+            i: self.i,
+            d: self.d,
+        })
+    }
+
+    fn update_schedule(&mut self, speed: Option<f32>, altitude: f32) -> Gains {
+        self.scheduled = self
+            .schedule
+            .as_ref()
+            .and_then(|schedule| schedule.gains(speed, altitude));
+        self.gains()
+    }
+
+    /// Returns the filtered derivative of the error
+    fn update_derivative(&mut self, error: f32, measurement: f32, dt: f32) -> f32 {
+        // there is no derivative on the first sample after a reset
+        let raw = if !self.initialized {
+            0.0
+        } else if self.d_on_measurement {
+            // the setpoint is assumed constant, so d(error) = -d(measurement)
+            -(measurement - self.last_measurement) / dt
+        } else {
+            (error - self.last_error) / dt
+        };
+        self.last_error = error;
+        self.last_measurement = measurement;
+        self.initialized = true;
+
+        self.derivative = match self.d_cutoff_hz {
+            Some(cutoff) => {
+                let rc = 1.0 / (2.0 * PI * cutoff);
+                let alpha = dt / (rc + dt);
+                self.derivative + alpha * (raw - self.derivative)
+            }
+            None => raw,
+        };
+        self.derivative
+    }
 
-        (p + i + d).clamp(-1.0, 1.0)
+    fn reset(&mut self) {
+        self.i_term = 0.0;
+        self.derivative = 0.0;
+        self.initialized = false;
+    }
+
+    /// Resets and sets the integrator so the output matches `output` for the current error
+    fn initialise(&mut self, setpoint: f32, measurement: f32, output: f32) {
+        self.reset();
+        let gains = self.gains();
+        if gains.i != 0.0 {
+            let error = setpoint - measurement;
+            self.i_term = ((output - gains.p * error) / gains.i).clamp(-self.i_limit, self.i_limit);
+        }
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
 pub struct ControlAction {
     pub port: f32,
     pub starboard: f32,
@@ -87,12 +195,30 @@ impl From<[f32; 4]> for ControlAction {
     }
 }
 
+impl From<ControlAction> for [f32; 4] {
+    fn from(action: ControlAction) -> Self {
+        [action.port, action.starboard, action.aft, action.rudder]
+    }
+}
+
 #[derive(Debug, Clone, Copy, Deserialize)]
 pub struct State {
     pub roll: f32,
     pub pitch: f32,
     pub yaw_rate: f32,
     pub altitude: f32,
+    /// only used by the inner loop of the cascaded structure
+    #[serde(default)]
+    pub pitch_rate: f32,
+    /// from the altitude estimator
+    #[serde(default)]
+    pub climb_rate: f32,
+    /// world frame, up is positive, without gravity
+    #[serde(default)]
+    pub vertical_acceleration: f32,
+    /// when the sensors measured the values above
+    #[serde(skip)]
+    pub sampled: SampleTimes,
 }
 
 impl Default for State {
@@ -102,6 +228,10 @@ impl Default for State {
             pitch: 0.0,
             yaw_rate: 0.0,
             altitude: 0.0,
+            pitch_rate: 0.0,
+            climb_rate: 0.0,
+            vertical_acceleration: 0.0,
+            sampled: SampleTimes::default(),
         }
     }
 }
@@ -115,6 +245,10 @@ impl Add for State {
             pitch: self.pitch + rhs.pitch,
             yaw_rate: self.yaw_rate + rhs.yaw_rate,
             altitude: self.altitude + rhs.altitude,
+            pitch_rate: self.pitch_rate + rhs.pitch_rate,
+            climb_rate: self.climb_rate + rhs.climb_rate,
+            vertical_acceleration: self.vertical_acceleration + rhs.vertical_acceleration,
+            sampled: self.sampled,
         }
     }
 }
@@ -138,16 +272,106 @@ impl Log for State {
                 name: "altitude",
                 value: self.altitude,
             },
+            Measurement {
+                name: "Pitch_Rate",
+                value: self.pitch_rate,
+            },
+            Measurement {
+                name: "Climb_Rate",
+                value: self.climb_rate,
+            },
+            Measurement {
+                name: "Vertical_Acceleration",
+                value: self.vertical_acceleration,
+            },
         ]
     }
 }
 
+impl From<[f32; 4]> for State {
+    fn from(vec: [f32; 4]) -> Self {
+        Self {
+            roll: vec[0],
+            pitch: vec[1],
+            yaw_rate: vec[2],
+            altitude: vec[3],
+            ..Self::default()
+        }
+    }
+}
+
 impl From<State> for [f32; 4] {
     fn from(state: State) -> Self {
         [state.roll, state.pitch, state.yaw_rate, state.altitude]
     }
 }
 
+/// A control law, turns setpoint and measurement into actuator angles
+pub trait Controller {
+    fn update(&mut self, setpoint: State, measurement: State, dt: f32) -> ControlAction;
+
+    /// Clears all internal state, e.g. integrators, while the controller is disabled
+    fn reset(&mut self);
+
+    /// Called when the controller is engaged, so it can continue smoothly from the current actuator angles
+    fn initialise(&mut self, _setpoint: State, _measurement: State, _action: ControlAction) {}
+
+    /// Runs the autotune while enabled, if the controller supports it
+    fn set_autotune(&mut self, _enabled: bool) {}
+
+    /// Controls the altitude while enabled, otherwise only the attitude. The altitude output fades in and out
+    fn set_altitude_hold(&mut self, _enabled: bool) {}
+
+    /// Internal values worth logging, with their measurement name
+    fn telemetry(&self) -> Vec<(&'static str, Arc<Mutex<dyn Log>>)> {
+        Vec::new()
+    }
+}
+
+/// The controller is selected by the `type` key
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ControllerConfig {
+    Pid(Box<FlightController>),
+    Lqr(Box<LqrController>),
+}
+
+impl ControllerConfig {
+    pub fn build(self, limits: ActuatorLimits) -> Box<dyn Controller> {
+        match self {
+            ControllerConfig::Pid(mut controller) => {
+                controller.limits = limits;
+                controller
+            }
+            ControllerConfig::Lqr(mut controller) => {
+                controller.limits = limits;
+                controller
+            }
+        }
+    }
+}
+
+/// Inner loops of the cascaded structure
+#[derive(Debug, Deserialize)]
+struct Cascade {
+    /// pitch setpoint change in degrees at full altitude output
+    pitch_authority: f32,
+    /// pitch rate setpoint in degrees/s at full pitch output
+    max_pitch_rate: f32,
+    pitch_rate: Pid,
+}
+
+/// How the altitude and pitch loops are connected
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Structure {
+    /// altitude and pitch act on the flaps independently through the mix matrix
+    #[default]
+    Parallel,
+    /// altitude -> pitch -> pitch rate, only the pitch rate loop acts on the flaps
+    Cascaded(Cascade),
+}
+
 #[derive(Debug, Deserialize)]
 pub struct FlightController {
     roll: Pid,
@@ -155,9 +379,41 @@ pub struct FlightController {
     yaw: Pid,
     altitude: Pid,
     mix_matrix: [[f32; 4]; 4],
+    /// the mixer serves the axes in this order when the actuators saturate
+    #[serde(default)]
+    axis_priority: AxisPriority,
+    #[serde(default)]
+    structure: Structure,
+    /// set from the top level config, shared with the servos
+    #[serde(skip_deserializing)]
+    pub limits: ActuatorLimits,
+    #[serde(default)]
+    autotune: Option<AutotuneConfig>,
+    #[serde(skip)]
+    tuner: Option<Autotune>,
+    /// the altitude loop is off
+    #[serde(skip)]
+    attitude_only: bool,
+    /// last output of the altitude loop, fades out while it is off
+    #[serde(skip)]
+    altitude_output: f32,
+    /// how fast the altitude output fades out, per second
+    #[serde(skip)]
+    altitude_fade: f32,
+    /// the altitude loop continues from the faded output with the next update
+    #[serde(skip)]
+    altitude_resume: bool,
 
     #[serde(skip_deserializing)]
     pub current_pid: Arc<Mutex<State>>,
+    #[serde(skip_deserializing)]
+    pub current_gains: Arc<Mutex<ActiveGains>>,
+    /// speed through the water in m/s for gain scheduling.
+    /// Nothing writes it yet, speed tables are rejected when loading until a speed sensor does
+    #[serde(skip_deserializing)]
+    pub speed: Arc<Mutex<Option<f32>>>,
+    #[serde(skip_deserializing)]
+    pub autotune_status: Arc<Mutex<AutotuneStatus>>,
 }
 
 impl FlightController {
@@ -167,36 +423,296 @@ impl FlightController {
         measurement: State,
         dt: f32,
     ) -> ControlAction {
-        let pid = State {
-            roll: self.roll.update(setpoint.roll, measurement.roll, dt),
-            pitch: self.pitch.update(setpoint.pitch, measurement.pitch, dt),
-            yaw_rate: self.yaw.update(setpoint.yaw_rate, measurement.yaw_rate, dt),
-            altitude: self
-                .altitude
-                .update(setpoint.altitude, measurement.altitude, dt),
+        self.update_schedules(measurement.altitude);
+        let tuning = self.tuner.as_mut().and_then(Autotune::axis);
+
+        let roll = self.roll.update(setpoint.roll, measurement.roll, dt);
+        let roll = self.tune(
+            tuning,
+            Axis::Roll,
+            setpoint.roll - measurement.roll,
+            roll,
+            dt,
+        );
+        let yaw_rate = self.yaw.update(setpoint.yaw_rate, measurement.yaw_rate, dt);
+        let yaw_rate = self.tune(
+            tuning,
+            Axis::Yaw,
+            setpoint.yaw_rate - measurement.yaw_rate,
+            yaw_rate,
+            dt,
+        );
+        let altitude = if self.attitude_only {
+            let step = self.altitude_fade * dt;
+            self.altitude_output - self.altitude_output.clamp(-step, step)
+        } else {
+            if std::mem::take(&mut self.altitude_resume) {
+                self.altitude.initialise(
+                    setpoint.altitude,
+                    measurement.altitude,
+                    self.altitude_output,
+                );
+            }
+            self.altitude
+                .update(setpoint.altitude, measurement.altitude, dt)
+        };
+        // in the cascade this replaces the offset of the pitch setpoint
+        let altitude = self.tune(
+            tuning,
+            Axis::Altitude,
+            setpoint.altitude - measurement.altitude,
+            altitude,
+            dt,
+        );
+        self.altitude_output = altitude;
+
+        // outputs of the loops, and what is fed into the mix matrix
+        let (pid, command) = match &mut self.structure {
+            Structure::Parallel => {
+                let pitch = self.pitch.update(setpoint.pitch, measurement.pitch, dt);
+                let pitch = self.tune(
+                    tuning,
+                    Axis::Pitch,
+                    setpoint.pitch - measurement.pitch,
+                    pitch,
+                    dt,
+                );
+                let pid = State {
+                    roll,
+                    pitch,
+                    yaw_rate,
+                    altitude,
+                    ..State::default()
+                };
+                (pid, pid)
+            }
+            Structure::Cascaded(cascade) => {
+                let pitch_setpoint = setpoint.pitch + altitude * cascade.pitch_authority;
+                let pitch = self.pitch.update(pitch_setpoint, measurement.pitch, dt);
+                let pitch_rate_setpoint = pitch * cascade.max_pitch_rate;
+                let pitch_rate =
+                    cascade
+                        .pitch_rate
+                        .update(pitch_rate_setpoint, measurement.pitch_rate, dt);
+                // the pitch axis of the mix is driven by the pitch rate loop
+                let pitch_rate = self.tune(
+                    tuning,
+                    Axis::Pitch,
+                    pitch_rate_setpoint - measurement.pitch_rate,
+                    pitch_rate,
+                    dt,
+                );
+                let pid = State {
+                    roll,
+                    pitch,
+                    yaw_rate,
+                    altitude,
+                    pitch_rate,
+                    ..State::default()
+                };
+                let command = State {
+                    pitch: pitch_rate,
+                    altitude: 0.0,
+                    ..pid
+                };
+                (pid, command)
+            }
         };
         *self.current_pid.lock().unwrap() = pid;
 
-        let mut action = [0.0; 4];
-        let pid_array: [f32; 4] = pid.into();
-        for i in 0..4 {
-            for j in 0..4 {
-                action[i] += self.mix_matrix[i][j] * pid_array[j];
+        let command: [f32; 4] = command.into();
+        let (action, achieved) = mix(
+            &self.mix_matrix,
+            &self.limits,
+            &self.axis_priority.0,
+            command,
+        );
+
+        // feed back what the actuators could do
+        self.roll.back_calculate(achieved[0], dt);
+        self.yaw.back_calculate(achieved[2], dt);
+        match &mut self.structure {
+            Structure::Parallel => {
+                self.pitch.back_calculate(achieved[1], dt);
+                self.altitude.back_calculate(achieved[3], dt);
+            }
+            Structure::Cascaded(cascade) => {
+                cascade.pitch_rate.back_calculate(achieved[1], dt);
+                self.pitch.back_calculate(pid.pitch, dt);
+                self.altitude.back_calculate(pid.altitude, dt);
+            }
+        }
+
+        action
+    }
+
+    fn update_schedules(&mut self, altitude: f32) {
+        let speed = *self.speed.lock().unwrap();
+        *self.current_gains.lock().unwrap() = ActiveGains {
+            roll: self.roll.update_schedule(speed, altitude),
+            pitch: self.pitch.update_schedule(speed, altitude),
+            yaw: self.yaw.update_schedule(speed, altitude),
+            altitude: self.altitude.update_schedule(speed, altitude),
+        };
+        if let Structure::Cascaded(cascade) = &mut self.structure {
+            cascade.pitch_rate.update_schedule(speed, altitude);
+        }
+    }
+
+    /// Sets the integrators so that the mixed output matches `action`
+    pub fn initialise(&mut self, setpoint: State, measurement: State, action: ControlAction) {
+        self.reset();
+        self.update_schedules(measurement.altitude);
+
+        let mut matrix = Matrix4::from_fn(|i, j| self.mix_matrix[i][j]);
+        if let Structure::Cascaded(_) = self.structure {
+            // the altitude column is unused
+            matrix.column_mut(Axis::Altitude as usize).fill(0.0);
+        }
+        // least squares if the actuators cannot be reached exactly
+        let command = match matrix.pseudo_inverse(1e-6) {
+            Ok(inverse) => inverse * Vector4::from(<[f32; 4]>::from(action)),
+            Err(_) => Vector4::zeros(),
+        };
+
+        self.roll
+            .initialise(setpoint.roll, measurement.roll, command[0]);
+        self.yaw
+            .initialise(setpoint.yaw_rate, measurement.yaw_rate, command[2]);
+        match &mut self.structure {
+            Structure::Parallel => {
+                self.pitch
+                    .initialise(setpoint.pitch, measurement.pitch, command[1]);
+                self.altitude
+                    .initialise(setpoint.altitude, measurement.altitude, command[3]);
+                self.altitude_output = command[3];
+            }
+            Structure::Cascaded(cascade) => {
+                // the outer loops ask for what the boat is doing right now
+                let pitch = measurement.pitch_rate / cascade.max_pitch_rate;
+                let altitude = (measurement.pitch - setpoint.pitch) / cascade.pitch_authority;
+                cascade.pitch_rate.initialise(
+                    pitch * cascade.max_pitch_rate,
+                    measurement.pitch_rate,
+                    command[1],
+                );
+                self.pitch.initialise(
+                    setpoint.pitch + altitude * cascade.pitch_authority,
+                    measurement.pitch,
+                    pitch,
+                );
+                self.altitude
+                    .initialise(setpoint.altitude, measurement.altitude, altitude);
+                self.altitude_output = altitude;
             }
         }
-        action.into()
+        // in attitude only the altitude output fades out from what was taken over
+        self.altitude_fade = self.altitude_output.abs() / ALTITUDE_FADE_S;
+    }
+
+    /// Replaces the output of the loop of `axis` with the relay while the autotune excites it.
+    /// `error` is the error of that loop
+    fn tune(&mut self, tuning: Option<Axis>, axis: Axis, error: f32, output: f32, dt: f32) -> f32 {
+        if tuning != Some(axis) {
+            return output;
+        }
+        let (Some(config), Some(tuner)) = (&self.autotune, &mut self.tuner) else {
+            return output;
+        };
+        let Some((relay, finished)) = tuner.update(config, error, dt) else {
+            return output;
+        };
+        if finished {
+            self.axis_pid(axis).reset();
+        }
+        relay
+    }
+
+    /// The PID acting on a column of the mix matrix, or on the pitch setpoint for the altitude in the cascade
+    fn axis_pid(&mut self, axis: Axis) -> &mut Pid {
+        match (axis, &mut self.structure) {
+            (Axis::Roll, _) => &mut self.roll,
+            (Axis::Pitch, Structure::Parallel) => &mut self.pitch,
+            (Axis::Pitch, Structure::Cascaded(cascade)) => &mut cascade.pitch_rate,
+            (Axis::Yaw, _) => &mut self.yaw,
+            (Axis::Altitude, _) => &mut self.altitude,
+        }
+    }
+
+    /// Name of the PID acting on a column of the mix matrix, as in the config
+    fn axis_loop(&self, axis: Axis) -> &'static str {
+        match (axis, &self.structure) {
+            (Axis::Pitch, Structure::Cascaded(_)) => "pitch_rate",
+            _ => axis.name(),
+        }
     }
 
     pub fn reset(&mut self) {
-        self.roll.i_term = 0.0;
-        self.pitch.i_term = 0.0;
-        self.yaw.i_term = 0.0;
-        self.altitude.i_term = 0.0;
+        self.tuner = None;
+        self.altitude_output = 0.0;
+        self.altitude_resume = false;
+        self.roll.reset();
+        self.pitch.reset();
+        self.yaw.reset();
+        self.altitude.reset();
+        if let Structure::Cascaded(cascade) = &mut self.structure {
+            cascade.pitch_rate.reset();
+        }
+    }
+}
+
+impl Controller for FlightController {
+    fn update(&mut self, setpoint: State, measurement: State, dt: f32) -> ControlAction {
+        self.update_controller(setpoint, measurement, dt)
+    }
+
+    fn reset(&mut self) {
+        FlightController::reset(self)
+    }
+
+    fn initialise(&mut self, setpoint: State, measurement: State, action: ControlAction) {
+        FlightController::initialise(self, setpoint, measurement, action)
+    }
+
+    fn set_autotune(&mut self, enabled: bool) {
+        match (&self.autotune, &self.tuner) {
+            (Some(config), None) if enabled => {
+                let loops = [Axis::Roll, Axis::Pitch, Axis::Yaw, Axis::Altitude]
+                    .map(|axis| self.axis_loop(axis));
+                self.tuner = Some(Autotune::new(config, loops, self.autotune_status.clone()))
+            }
+            (_, Some(_)) if !enabled => {
+                self.tuner = None;
+                self.autotune_status.lock().unwrap().axis = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn set_altitude_hold(&mut self, enabled: bool) {
+        if enabled != self.attitude_only {
+            return;
+        }
+        if enabled {
+            self.altitude_resume = true;
+        } else {
+            // the last output is held and ramped down instead of stepping to zero
+            self.altitude_fade = self.altitude_output.abs() / ALTITUDE_FADE_S;
+        }
+        self.attitude_only = !enabled;
+    }
+
+    fn telemetry(&self) -> Vec<(&'static str, Arc<Mutex<dyn Log>>)> {
+        vec![
+            ("pid", self.current_pid.clone()),
+            ("gains", self.current_gains.clone()),
+            ("autotune", self.autotune_status.clone()),
+        ]
     }
 }
 #[cfg(test)]
 mod tests {
-    use super::Pid;
+    use super::{ControlAction, Controller, FlightController, Pid, State};
 
     #[test]
     fn test_clamp() {
@@ -205,10 +721,254 @@ mod tests {
             i: 0.0,
             d: 0.0,
             i_limit: 1.0,
-            i_term: 0.0,
-            last_error: 0.0,
+            ..Pid::default()
         };
 
         assert_eq!(-1.0, pid.update(0.0, 1.0, 1.0));
     }
+
+    #[test]
+    fn test_back_calculation() {
+        let mut pid = Pid {
+            p: 0.5,
+            i: 1.0,
+            i_limit: 10.0,
+            ..Pid::default()
+        };
+
+        for _ in 0..100 {
+            let output = pid.update(4.0, 0.0, 0.1);
+            pid.back_calculate(output, 0.1);
+        }
+        // without feedback the integrator would sit at the limit
+        assert!(pid.i_term < 2.0);
+    }
+
+    #[test]
+    fn test_derivative_sign() {
+        let mut pid = Pid {
+            d: 0.1,
+            i_limit: 1.0,
+            ..Pid::default()
+        };
+
+        assert_eq!(0.0, pid.update(0.0, 0.0, 0.1));
+        // the measurement moves away from the setpoint, the d term pushes back
+        assert!(pid.update(0.0, 0.1, 0.1) < 0.0);
+    }
+
+    #[test]
+    fn test_derivative_on_measurement() {
+        let mut pid = Pid {
+            d: 0.1,
+            i_limit: 1.0,
+            d_on_measurement: true,
+            ..Pid::default()
+        };
+
+        pid.update(0.0, 0.0, 0.1);
+        // no kick on a setpoint step
+        assert_eq!(0.0, pid.update(1.0, 0.0, 0.1));
+        assert!(pid.update(1.0, 0.1, 0.1) < 0.0);
+    }
+
+    #[test]
+    fn test_derivative_filter() {
+        let mut pid = Pid {
+            d: 1.0,
+            i_limit: 1.0,
+            d_cutoff_hz: Some(1.0),
+            ..Pid::default()
+        };
+
+        pid.update(0.0, 0.0, 0.01);
+        pid.update(0.0, 0.001, 0.01);
+        // unfiltered the derivative would be -0.1
+        assert!(pid.derivative > -0.1 && pid.derivative < 0.0);
+    }
+
+    #[test]
+    fn test_cascaded() {
+        let mut controller: FlightController = serde_yaml::from_str(
+            "
+roll: { p: 0.04, i: 0.0, d: 0.0, i_limit: 25.0 }
+pitch: { p: 0.1, i: 0.0, d: 0.0, i_limit: 25.0 }
+yaw: { p: 0.3, i: 0.0, d: 0.0, i_limit: 25.0 }
+altitude: { p: 4.0, i: 0.0, d: 0.0, i_limit: 5.0 }
+structure:
+  type: cascaded
+  pitch_authority: 5.0
+  max_pitch_rate: 30.0
+  pitch_rate: { p: 0.05, i: 0.0, d: 0.0, i_limit: 25.0 }
+mix_matrix:
+  - [ 15.0, 0.0, 0.0, -20.0]
+  - [-15.0, 0.0, 0.0, -15.0]
+  - [ 0.0, 15.0, 0.0, -15.0]
+  - [ 0.0,  0.0, 1.0,  0.0]
+",
+        )
+        .unwrap();
+
+        let setpoint = State {
+            altitude: 0.1,
+            ..State::default()
+        };
+        let action = controller.update_controller(setpoint, State::default(), 0.01);
+
+        // too low: the altitude loop asks for more pitch, only the pitch rate loop reaches the flaps
+        assert_eq!(0.0, action.port);
+        assert_eq!(0.0, action.starboard);
+        assert!(action.aft > 0.0);
+        let pid = *controller.current_pid.lock().unwrap();
+        assert!(pid.altitude > 0.0 && pid.pitch > 0.0 && pid.pitch_rate > 0.0);
+    }
+
+    #[test]
+    fn test_initialise() {
+        let mut controller: FlightController = serde_yaml::from_str(
+            "
+roll: { p: 0.04, i: 0.1, d: 0.0, i_limit: 25.0 }
+pitch: { p: 0.1, i: 0.1, d: 0.0, i_limit: 25.0 }
+yaw: { p: 0.3, i: 0.1, d: 0.0, i_limit: 25.0 }
+altitude: { p: 4.0, i: 1.0, d: 0.0, i_limit: 5.0 }
+mix_matrix:
+  - [ 15.0, 0.0, 0.0, -20.0]
+  - [-15.0, 0.0, 0.0, -15.0]
+  - [ 0.0, 15.0, 0.0, -15.0]
+  - [ 0.0,  0.0, 1.0,  0.0]
+",
+        )
+        .unwrap();
+
+        let held = ControlAction {
+            port: 3.0,
+            starboard: -2.0,
+            aft: 1.0,
+            rudder: 0.5,
+        };
+        let setpoint = State {
+            altitude: 0.3,
+            ..State::default()
+        };
+        controller.initialise(setpoint, State::default(), held);
+        // the first step continues from the angles the controller took over
+        let action: [f32; 4] = controller
+            .update_controller(setpoint, State::default(), 0.0)
+            .into();
+        let held: [f32; 4] = held.into();
+        for i in 0..4 {
+            assert!((action[i] - held[i]).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_altitude_fallback() {
+        let mut controller: FlightController = serde_yaml::from_str(
+            "
+roll: { p: 0.04, i: 0.0, d: 0.0, i_limit: 25.0 }
+pitch: { p: 0.1, i: 0.0, d: 0.0, i_limit: 25.0 }
+yaw: { p: 0.3, i: 0.0, d: 0.0, i_limit: 25.0 }
+altitude: { p: 4.0, i: 1.0, d: 0.0, i_limit: 5.0 }
+mix_matrix:
+  - [ 15.0, 0.0, 0.0, -20.0]
+  - [-15.0, 0.0, 0.0, -15.0]
+  - [ 0.0, 15.0, 0.0, -15.0]
+  - [ 0.0,  0.0, 1.0,  0.0]
+",
+        )
+        .unwrap();
+        let setpoint = State {
+            altitude: 0.1,
+            ..State::default()
+        };
+        let altitude = |controller: &mut FlightController| {
+            controller.update_controller(setpoint, State::default(), 0.01);
+            controller.current_pid.lock().unwrap().altitude
+        };
+
+        let held = altitude(&mut controller);
+        assert!(held > 0.0);
+
+        // the sonar is lost: the output ramps down over a second instead of stepping to zero
+        controller.set_altitude_hold(false);
+        let faded = altitude(&mut controller);
+        assert!(faded < held && faded > 0.9 * held);
+        for _ in 0..50 {
+            altitude(&mut controller);
+        }
+        let faded = altitude(&mut controller);
+        assert!(faded > 0.0 && faded < 0.6 * held);
+
+        // back in altitude hold the loop continues from the faded output
+        controller.set_altitude_hold(true);
+        assert!((altitude(&mut controller) - faded).abs() < 0.01);
+
+        // and fades out completely
+        controller.set_altitude_hold(false);
+        for _ in 0..100 {
+            altitude(&mut controller);
+        }
+        assert_eq!(0.0, altitude(&mut controller));
+    }
+
+    #[test]
+    fn test_cascaded_autotune() {
+        let config = "
+roll: { p: 0.04, i: 0.0, d: 0.0, i_limit: 25.0 }
+pitch: { p: 0.1, i: 0.0, d: 0.0, i_limit: 25.0 }
+yaw: { p: 0.3, i: 0.0, d: 0.0, i_limit: 25.0 }
+altitude: { p: 4.0, i: 0.0, d: 0.0, i_limit: 5.0 }
+structure:
+  type: cascaded
+  pitch_authority: 5.0
+  max_pitch_rate: 30.0
+  pitch_rate: { p: 0.05, i: 0.0, d: 0.0, i_limit: 25.0 }
+mix_matrix:
+  - [ 15.0, 0.0, 0.0, -20.0]
+  - [-15.0, 0.0, 0.0, -15.0]
+  - [ 0.0, 15.0, 0.0, -15.0]
+  - [ 0.0,  0.0, 1.0,  0.0]
+autotune:
+  rule: ziegler_nichols
+  cycles: 2
+  timeout_s: 10.0
+  results_path: /dev/null
+  axes:
+";
+        let tuned = |axis: &str, measurement: State| {
+            let mut controller: FlightController = serde_yaml::from_str(&format!(
+                "{}    - {{ axis: {}, amplitude: 0.2, hysteresis: 0.0 }}",
+                config, axis
+            ))
+            .unwrap();
+            controller.set_autotune(true);
+            controller.update_controller(State::default(), measurement, 0.01);
+            let pid = *controller.current_pid.lock().unwrap();
+            pid
+        };
+
+        // level but pitching up: the relay acts on the pitch rate error and replaces the pitch rate loop
+        let pid = tuned(
+            "pitch",
+            State {
+                pitch_rate: 5.0,
+                ..State::default()
+            },
+        );
+        assert_eq!(-0.2, pid.pitch_rate);
+
+        // too high: the relay replaces the altitude loop, which moves the pitch setpoint
+        let pid = tuned(
+            "altitude",
+            State {
+                altitude: 0.1,
+                ..State::default()
+            },
+        );
+        assert_eq!(-0.2, pid.altitude);
+        assert!(pid.pitch < 0.0);
+
+        let zero_cycles = config.replace("cycles: 2", "cycles: 0");
+        assert!(serde_yaml::from_str::<FlightController>(&zero_cycles).is_err());
+    }
 }
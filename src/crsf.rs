@@ -0,0 +1,156 @@
+use crate::sbus::unpack_channels;
+
+/// Address of the flight controller, first byte of every frame the receiver sends
+const SYNC: u8 = 0xC8;
+/// type, up to 60 bytes payload and crc
+const MAX_LEN: usize = 62;
+
+const TYPE_LINK_STATISTICS: u8 = 0x14;
+const TYPE_RC_CHANNELS: u8 = 0x16;
+
+/// Uplink quality as seen by the receiver
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkStatistics {
+    /// dBm of the better antenna
+    pub rssi: i16,
+    /// percent of received packets
+    pub link_quality: u8,
+    /// dB
+    pub snr: i8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrsfPacket {
+    /// pulse widths in µs
+    Channels([u16; 16]),
+    LinkStatistics(LinkStatistics),
+}
+
+/// CRC-8 DVB-S2 over type and payload
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0xD5
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+/// Parser for CRSF frames as sent by Crossfire and ExpressLRS receivers:
+/// sync, length, type, payload, crc. The length counts type, payload and crc.
+/// After a bad frame the parser waits for the next sync byte, frames of other types are skipped
+#[derive(Debug)]
+pub struct CrsfParser {
+    frame: [u8; MAX_LEN + 2],
+    len: usize,
+    pub good_frames: u32,
+    pub bad_frames: u32,
+}
+
+impl Default for CrsfParser {
+    fn default() -> Self {
+        Self {
+            frame: [0; MAX_LEN + 2],
+            len: 0,
+            good_frames: 0,
+            bad_frames: 0,
+        }
+    }
+}
+
+impl CrsfParser {
+    /// Feeds one byte. Returns the packet when it completes a valid channel or link statistics frame
+    pub fn push(&mut self, byte: u8) -> Option<CrsfPacket> {
+        if self.len == 0 && byte != SYNC {
+            return None;
+        }
+        if self.len == 1 && !(2..=MAX_LEN).contains(&(byte as usize)) {
+            self.bad_frames += 1;
+            self.len = 0;
+            return None;
+        }
+        self.frame[self.len] = byte;
+        self.len += 1;
+        if self.len < 2 || self.len < self.frame[1] as usize + 2 {
+            return None;
+        }
+
+        let frame = &self.frame[2..self.len];
+        self.len = 0;
+        let (checksum, data) = frame.split_last().unwrap();
+        if crc8(data) != *checksum {
+            self.bad_frames += 1;
+            return None;
+        }
+        self.good_frames += 1;
+        let (frame_type, payload) = data.split_first().unwrap();
+        match *frame_type {
+            TYPE_RC_CHANNELS if payload.len() >= 22 => {
+                Some(CrsfPacket::Channels(unpack_channels(&payload[..22])))
+            }
+            TYPE_LINK_STATISTICS if payload.len() >= 10 => {
+                Some(CrsfPacket::LinkStatistics(LinkStatistics {
+                    // sent as positive numbers
+                    rssi: -(payload[0].min(payload[1]) as i16),
+                    link_quality: payload[2],
+                    snr: payload[3] as i8,
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc8, CrsfPacket, CrsfParser, LinkStatistics};
+
+    fn frame(frame_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![frame_type];
+        data.extend(payload);
+        let mut frame = vec![0xC8, data.len() as u8 + 1];
+        frame.extend(&data);
+        frame.push(crc8(&data));
+        frame
+    }
+
+    #[test]
+    fn test_crsf() {
+        let mut parser = CrsfParser::default();
+        // every channel at 992 is a repeating 11 bit pattern
+        let mut channels = [0u8; 22];
+        for i in 0..16 {
+            for b in 0..11 {
+                if 992 & (1 << b) != 0 {
+                    let bit = i * 11 + b;
+                    channels[bit / 8] |= 1 << (bit % 8);
+                }
+            }
+        }
+        let mut bytes = vec![0x00, 0xC8, 0xFF];
+        bytes.extend(frame(0x16, &channels));
+        let mut corrupted = frame(0x16, &channels);
+        corrupted[5] ^= 0x01;
+        bytes.extend(corrupted);
+        bytes.extend(frame(0x14, &[70, 65, 100, 9, 0, 4, 3, 60, 100, 8]));
+        let packets: Vec<_> = bytes.iter().filter_map(|b| parser.push(*b)).collect();
+
+        assert_eq!(
+            vec![
+                CrsfPacket::Channels([1500; 16]),
+                CrsfPacket::LinkStatistics(LinkStatistics {
+                    rssi: -65,
+                    link_quality: 100,
+                    snr: 9,
+                })
+            ],
+            packets
+        );
+        assert_eq!(2, parser.bad_frames);
+    }
+}
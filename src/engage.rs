@@ -0,0 +1,211 @@
+use crate::control::{ControlAction, Controller, State};
+use crate::flight_mode::FlightMode;
+use crate::mixer::{manual_mix, ActuatorLimits};
+use crate::receiver::Inputs;
+
+/// Who drives the actuators
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum Source {
+    Controller,
+    /// the pilot through the manual mix
+    Manual,
+    #[default]
+    Trim,
+}
+
+/// Decides who drives the actuators: the controller, the pilot through the manual mix, or nobody (trim).
+/// When the controller is engaged it is initialised to continue from the current actuator angles,
+/// on any other change the actuators are ramped to trim or the manual angles instead of jumping there
+#[derive(Debug, Default)]
+pub struct Engagement {
+    ramp_time: f32,
+    manual_mix: [[f32; 4]; 4],
+    limits: ActuatorLimits,
+    source: Source,
+    /// angles when the source changed and the progress of the ramp from 0 to 1
+    ramp: Option<(ControlAction, f32)>,
+}
+
+impl Engagement {
+    /// `ramp_time` in seconds, zero returns to trim immediately
+    pub fn new(ramp_time: f32, manual_mix: [[f32; 4]; 4], limits: ActuatorLimits) -> Self {
+        Self {
+            ramp_time,
+            manual_mix,
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the actuator angles for this control step. `last` are the angles of the previous step
+    pub fn update(
+        &mut self,
+        controller: &mut dyn Controller,
+        inputs: &Inputs,
+        measurement: State,
+        last: ControlAction,
+        dt: f32,
+    ) -> ControlAction {
+        let source = if inputs.mode.controller() {
+            Source::Controller
+        } else if inputs.mode == FlightMode::Manual {
+            Source::Manual
+        } else {
+            Source::Trim
+        };
+        if source != self.source {
+            if self.source == Source::Controller {
+                controller.reset();
+            }
+            if source == Source::Controller {
+                controller.initialise(inputs.setpoint, measurement, last);
+                self.ramp = None;
+            } else {
+                self.ramp = Some((last, 0.0));
+            }
+            self.source = source;
+        }
+
+        let target = match source {
+            Source::Controller => return controller.update(inputs.setpoint, measurement, dt),
+            Source::Manual => manual_mix(&self.manual_mix, &self.limits, inputs.sticks),
+            Source::Trim => ControlAction::default(),
+        };
+        match &mut self.ramp {
+            Some((from, progress)) if self.ramp_time > 0.0 => {
+                *progress = (*progress + dt / self.ramp_time).min(1.0);
+                let from: [f32; 4] = (*from).into();
+                let target: [f32; 4] = target.into();
+                let mut action = [0.0; 4];
+                for i in 0..4 {
+                    action[i] = from[i] + (target[i] - from[i]) * *progress;
+                }
+                if *progress >= 1.0 {
+                    self.ramp = None;
+                }
+                action.into()
+            }
+            _ => target,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Engagement;
+    use crate::control::{ControlAction, Controller, State};
+    use crate::flight_mode::FlightMode;
+    use crate::mixer::ActuatorLimits;
+    use crate::receiver::Inputs;
+
+    /// Integrator only, so the output is all state
+    struct Integrator {
+        output: f32,
+    }
+
+    impl Controller for Integrator {
+        fn update(&mut self, setpoint: State, measurement: State, dt: f32) -> ControlAction {
+            self.output += dt * (setpoint.roll - measurement.roll);
+            ControlAction {
+                port: self.output,
+                ..ControlAction::default()
+            }
+        }
+
+        fn reset(&mut self) {
+            self.output = 0.0;
+        }
+
+        fn initialise(&mut self, _setpoint: State, _measurement: State, action: ControlAction) {
+            self.output = action.port;
+        }
+    }
+
+    #[test]
+    fn test_bumpless() {
+        let mut controller = Integrator { output: 0.0 };
+        let mut engagement = Engagement::new(1.0, [[0.0; 4]; 4], ActuatorLimits::default());
+        let state = State::default();
+        let mut inputs = Inputs {
+            mode: FlightMode::Stabilise,
+            ..Inputs::default()
+        };
+        let held = ControlAction {
+            port: 4.0,
+            ..ControlAction::default()
+        };
+
+        // engaging continues from the angles the pilot left
+        let action = engagement.update(&mut controller, &inputs, state, held, 0.1);
+        assert_eq!(4.0, action.port);
+
+        // disengaging ramps back to trim
+        inputs.mode = FlightMode::Off;
+        let action = engagement.update(&mut controller, &inputs, state, action, 0.25);
+        assert!((action.port - 3.0).abs() < 1e-5);
+        for _ in 0..3 {
+            engagement.update(&mut controller, &inputs, state, action, 0.25);
+        }
+        let action = engagement.update(&mut controller, &inputs, state, action, 0.25);
+        assert_eq!(ControlAction::default(), action);
+    }
+
+    #[test]
+    fn test_manual() {
+        let mut controller = Integrator { output: 0.0 };
+        let mix = [
+            [10.0, 0.0, 0.0, 0.0],
+            [-10.0, 0.0, 0.0, 0.0],
+            [0.0, 10.0, 0.0, 0.0],
+            [0.0, 0.0, 30.0, 0.0],
+        ];
+        let mut engagement = Engagement::new(0.0, mix, ActuatorLimits::default());
+        let inputs = Inputs {
+            mode: FlightMode::Manual,
+            sticks: [0.5, 0.0, 2.0, 0.0],
+            ..Inputs::default()
+        };
+
+        // the sticks drive the actuators through the manual mix, the limits still apply
+        let action = engagement.update(
+            &mut controller,
+            &inputs,
+            State::default(),
+            ControlAction::default(),
+            0.01,
+        );
+        assert_eq!(5.0, action.port);
+        assert_eq!(-5.0, action.starboard);
+        assert_eq!(45.0, action.rudder);
+    }
+
+    #[test]
+    fn test_manual_to_off() {
+        let mut controller = Integrator { output: 0.0 };
+        let mix = [[10.0, 0.0, 0.0, 0.0], [0.0; 4], [0.0; 4], [0.0; 4]];
+        let mut engagement = Engagement::new(1.0, mix, ActuatorLimits::default());
+        let state = State::default();
+        let mut inputs = Inputs {
+            mode: FlightMode::Manual,
+            sticks: [0.8, 0.0, 0.0, 0.0],
+            ..Inputs::default()
+        };
+
+        // the sticks are ramped in from trim
+        let mut action = engagement.update(
+            &mut controller,
+            &inputs,
+            state,
+            ControlAction::default(),
+            0.5,
+        );
+        assert!((action.port - 4.0).abs() < 1e-5);
+        action = engagement.update(&mut controller, &inputs, state, action, 0.5);
+        assert_eq!(8.0, action.port);
+
+        // the failsafe turns it off, back to trim without a step
+        inputs.mode = FlightMode::Off;
+        action = engagement.update(&mut controller, &inputs, state, action, 0.25);
+        assert!((action.port - 6.0).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,186 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use nalgebra::{Matrix2, RowVector2, Vector2};
+use serde::Deserialize;
+
+use crate::control::State;
+use crate::sonar_filter::SonarStatus;
+
+/// Noise of the altitude estimator inputs, as standard deviations
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct EstimatorConfig {
+    /// vertical acceleration of the IMU in m/s²
+    pub acceleration_noise: f32,
+    /// sonar distance in m
+    pub sonar_noise: f32,
+    /// once the sonar filter has rejected the samples for this long the altitude is held, in s
+    pub hold_after_s: f32,
+}
+
+impl Default for EstimatorConfig {
+    fn default() -> Self {
+        Self {
+            acceleration_noise: 0.5,
+            sonar_noise: 0.03,
+            hold_after_s: 0.5,
+        }
+    }
+}
+
+/// Kalman filter on altitude and climb rate.
+/// The vertical acceleration of the IMU drives the prediction every control step,
+/// every new sonar sample corrects it.
+/// Once the sonar filter has rejected the samples for too long the last estimate is held instead of integrating the IMU.
+/// A sonar that stops answering altogether is left to the max age of the altitude
+pub struct AltitudeEstimator {
+    config: EstimatorConfig,
+    /// altitude and climb rate
+    x: Vector2<f32>,
+    p: Matrix2<f32>,
+    /// sample time of the last sonar reading used
+    last_sonar: Option<Instant>,
+    /// for logging
+    pub estimate: Arc<Mutex<State>>,
+}
+
+impl AltitudeEstimator {
+    pub fn new(config: EstimatorConfig) -> Self {
+        Self {
+            config,
+            x: Vector2::zeros(),
+            p: Matrix2::identity(),
+            last_sonar: None,
+            estimate: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    fn predict(&mut self, acceleration: f32, dt: f32) {
+        let f = Matrix2::new(1.0, dt, 0.0, 1.0);
+        let g = Vector2::new(0.5 * dt * dt, dt);
+        self.x = f * self.x + g * acceleration;
+        let q = g * g.transpose() * self.config.acceleration_noise.powi(2);
+        self.p = f * self.p * f.transpose() + q;
+    }
+
+    fn correct(&mut self, sonar: f32) {
+        let h = RowVector2::new(1.0, 0.0);
+        let innovation = sonar - self.x[0];
+        let s = (h * self.p * h.transpose())[0] + self.config.sonar_noise.powi(2);
+        let k = self.p * h.transpose() / s;
+        self.x += k * innovation;
+        self.p = (Matrix2::identity() - k * h) * self.p;
+    }
+
+    /// Runs one control step with the validity of the sonar. Returns the measurement with the raw sonar altitude
+    /// replaced by the estimate, and the estimated climb rate
+    pub fn update(&mut self, measurement: State, sonar: SonarStatus, dt: f32) -> State {
+        let holding = !sonar.valid && sonar.age >= self.config.hold_after_s;
+        let new_sample = measurement
+            .sampled
+            .altitude
+            .filter(|sampled| Some(*sampled) != self.last_sonar);
+        match (self.last_sonar, new_sample) {
+            (None, None) => {}
+            // start at the first reading
+            (None, Some(_)) => {
+                self.x = Vector2::new(measurement.altitude, 0.0);
+                self.p = Matrix2::new(self.config.sonar_noise.powi(2), 0.0, 0.0, 1.0);
+            }
+            (Some(_), Some(_)) => {
+                self.predict(measurement.vertical_acceleration, dt);
+                self.correct(measurement.altitude);
+            }
+            (Some(_), None) if holding => self.x[1] = 0.0,
+            (Some(_), None) => self.predict(measurement.vertical_acceleration, dt),
+        }
+        if new_sample.is_some() {
+            self.last_sonar = new_sample;
+        }
+
+        let estimate = State {
+            altitude: self.x[0],
+            climb_rate: self.x[1],
+            ..measurement
+        };
+        *self.estimate.lock().unwrap() = estimate;
+        estimate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AltitudeEstimator, EstimatorConfig};
+    use crate::control::State;
+    use crate::sonar_filter::SonarStatus;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_climb() {
+        let mut estimator = AltitudeEstimator::new(EstimatorConfig::default());
+        let dt = 0.01;
+        let mut estimate = State::default();
+        let mut measurement = State::default();
+        let start = Instant::now();
+        let valid = SonarStatus {
+            valid: true,
+            ..SonarStatus::default()
+        };
+        // climbing at 0.2 m/s, the sonar reports every 10 steps
+        for k in 0..500 {
+            let altitude = 0.2 * (k / 10 * 10) as f32 * dt;
+            measurement.altitude = altitude;
+            measurement.sampled.altitude = Some(start + Duration::from_millis(k / 10 * 100));
+            estimate = estimator.update(measurement, valid, dt);
+        }
+        assert!((estimate.climb_rate - 0.2).abs() < 0.02);
+        assert!((estimate.altitude - 1.0).abs() < 0.05);
+
+        // the filter rejects the sonar, the estimate is held
+        let rejected = SonarStatus {
+            valid: false,
+            age: 1.0,
+            ..SonarStatus::default()
+        };
+        estimate = estimator.update(measurement, rejected, dt);
+        assert_eq!(0.0, estimate.climb_rate);
+        assert_eq!(
+            estimate.altitude,
+            estimator.update(measurement, rejected, dt).altitude
+        );
+
+        // a short dropout still follows the IMU
+        let dropout = SonarStatus {
+            age: 0.1,
+            ..rejected
+        };
+        measurement.vertical_acceleration = 1.0;
+        assert!(estimator.update(measurement, dropout, dt).climb_rate > 0.0);
+    }
+
+    #[test]
+    fn test_steady_height() {
+        let mut estimator = AltitudeEstimator::new(EstimatorConfig::default());
+        let dt = 0.01;
+        let mut estimate = State::default();
+        // the same reading every time while the IMU has a bias, each new sample still corrects
+        let mut measurement = State {
+            altitude: 0.5,
+            vertical_acceleration: 0.1,
+            ..State::default()
+        };
+        let valid = SonarStatus {
+            valid: true,
+            ..SonarStatus::default()
+        };
+        let start = Instant::now();
+        for k in 0..500 {
+            measurement.sampled.altitude = Some(start + Duration::from_millis(k / 10 * 100));
+            estimate = estimator.update(measurement, valid, dt);
+        }
+        assert!((estimate.altitude - 0.5).abs() < 0.05);
+        // uncertain only by the predictions since the last sample
+        assert!(estimator.p[(0, 0)].sqrt() < 0.03);
+    }
+}
@@ -0,0 +1,241 @@
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::control::State;
+use crate::flight_mode::FlightMode;
+use crate::influx::{Log, Measurement};
+use crate::receiver::Inputs;
+
+/// What the boat does while the RC link is lost
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailsafeAction {
+    /// keep flying the last inputs
+    Hold,
+    /// fly straight in altitude hold and lower the altitude setpoint until the hull is in the water
+    Land,
+    /// return the actuators to trim
+    Disengage,
+}
+
+/// Pulse width in µs a channel is set to by the transmitter's own failsafe
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct FailsafePulse {
+    pub channel: usize,
+    pub pulse: u16,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FailsafeConfig {
+    /// the link is lost without a valid frame for this long
+    pub timeout_ms: u64,
+    pub action: FailsafeAction,
+    /// m/s the altitude setpoint is lowered with when landing
+    pub land_rate: f32,
+    /// altitude setpoint the landing ends at, in meter
+    pub land_altitude: f32,
+    /// frames with all of these values are sent by the receiver after it lost the transmitter, they count as missing. Empty disables the check
+    pub pulses: Vec<FailsafePulse>,
+}
+
+impl Default for FailsafeConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 200,
+            action: FailsafeAction::Disengage,
+            land_rate: 0.05,
+            land_altitude: 0.0,
+            pulses: Vec::new(),
+        }
+    }
+}
+
+impl FailsafeConfig {
+    /// True if the raw channel values are the transmitter's failsafe values
+    pub fn transmitter_failsafe(&self, raw: &[u16]) -> bool {
+        const TOLERANCE: u16 = 10;
+        !self.pulses.is_empty()
+            && self.pulses.iter().all(|failsafe| {
+                raw.get(failsafe.channel)
+                    .is_some_and(|pulse| pulse.abs_diff(failsafe.pulse) <= TOLERANCE)
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FailsafeStatus {
+    pub link_lost: bool,
+    /// seconds since the last valid frame, -1 before the first one
+    pub frame_age: f32,
+    /// number of times the link was lost
+    pub losses: u32,
+}
+
+impl Log for FailsafeStatus {
+    fn measurements(&self) -> Vec<Measurement> {
+        vec![
+            Measurement {
+                name: "link_lost",
+                value: self.link_lost as u8 as f32,
+            },
+            Measurement {
+                name: "frame_age",
+                value: self.frame_age,
+            },
+            Measurement {
+                name: "losses",
+                value: self.losses as f32,
+            },
+        ]
+    }
+}
+
+/// Replaces the pilot inputs while no valid frames arrive
+pub struct Failsafe {
+    timeout: Duration,
+    action: FailsafeAction,
+    land_rate: f32,
+    land_altitude: f32,
+    default_setpoint: State,
+    /// altitude setpoint while landing
+    altitude: Option<f32>,
+    pub status: Arc<Mutex<FailsafeStatus>>,
+}
+
+impl Failsafe {
+    pub fn new(
+        config: &FailsafeConfig,
+        default_setpoint: State,
+        status: Arc<Mutex<FailsafeStatus>>,
+    ) -> Self {
+        Self {
+            timeout: Duration::from_millis(config.timeout_ms),
+            action: config.action,
+            land_rate: config.land_rate,
+            land_altitude: config.land_altitude,
+            default_setpoint,
+            altitude: None,
+            status,
+        }
+    }
+
+    pub fn update(&mut self, inputs: &mut Inputs, now: Instant, dt: f32) {
+        let age = inputs
+            .received
+            .map(|received| now.saturating_duration_since(received));
+        // never having had a link counts as lost
+        let lost = age.is_none_or(|age| age > self.timeout);
+        {
+            let mut status = self.status.lock().unwrap();
+            if lost && !status.link_lost {
+                println!("[Failsafe] RC link lost");
+                status.losses += 1;
+            }
+            status.link_lost = lost;
+            status.frame_age = age.map_or(-1.0, |age| age.as_secs_f32());
+        }
+        if !lost {
+            self.altitude = None;
+            return;
+        }
+
+        if inputs.mode == FlightMode::Autotune {
+            inputs.mode = FlightMode::AltitudeHold;
+        }
+        inputs.calibrate = false;
+        match self.action {
+            FailsafeAction::Hold => {}
+            FailsafeAction::Land => {
+                let altitude = self.altitude.unwrap_or(inputs.setpoint.altitude);
+                let altitude = (altitude - self.land_rate * dt).max(self.land_altitude);
+                self.altitude = Some(altitude);
+                if inputs.mode != FlightMode::Off {
+                    inputs.mode = FlightMode::AltitudeHold;
+                }
+                inputs.sticks = [0.0; 4];
+                inputs.setpoint = State {
+                    altitude,
+                    ..self.default_setpoint
+                };
+            }
+            FailsafeAction::Disengage => inputs.mode = FlightMode::Off,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Failsafe, FailsafeAction, FailsafeConfig, FailsafePulse};
+    use crate::control::State;
+    use crate::flight_mode::FlightMode;
+    use crate::receiver::Inputs;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_land() {
+        let config = FailsafeConfig {
+            action: FailsafeAction::Land,
+            land_rate: 0.1,
+            ..FailsafeConfig::default()
+        };
+        let status = Arc::new(Mutex::new(Default::default()));
+        let mut failsafe = Failsafe::new(&config, State::default(), status.clone());
+        let start = Instant::now();
+        let received = Inputs {
+            setpoint: State {
+                roll: 5.0,
+                altitude: 0.3,
+                ..State::default()
+            },
+            mode: FlightMode::Manual,
+            received: Some(start),
+            ..Inputs::default()
+        };
+
+        let mut inputs = received;
+        failsafe.update(&mut inputs, start + Duration::from_millis(100), 0.01);
+        assert_eq!(5.0, inputs.setpoint.roll);
+        assert!(!status.lock().unwrap().link_lost);
+
+        // the last frame stays in the inputs
+        let mut altitude = 0.3;
+        for k in 0..400 {
+            let mut inputs = received;
+            failsafe.update(&mut inputs, start + Duration::from_millis(300 + k), 0.01);
+            assert_eq!(FlightMode::AltitudeHold, inputs.mode);
+            assert_eq!(0.0, inputs.setpoint.roll);
+            assert!(inputs.setpoint.altitude <= altitude);
+            altitude = inputs.setpoint.altitude;
+        }
+        assert_eq!(0.0, altitude);
+        assert_eq!(1, status.lock().unwrap().losses);
+    }
+
+    #[test]
+    fn test_transmitter_failsafe() {
+        let config = FailsafeConfig {
+            pulses: vec![FailsafePulse {
+                channel: 2,
+                pulse: 900,
+            }],
+            ..FailsafeConfig::default()
+        };
+        let mut raw = [1500u16; 14];
+        assert!(!config.transmitter_failsafe(&raw));
+        raw[2] = 905;
+        assert!(config.transmitter_failsafe(&raw));
+        assert!(!FailsafeConfig::default().transmitter_failsafe(&raw));
+
+        // disengaged without a link
+        let mut failsafe = Failsafe::new(&config, State::default(), Arc::default());
+        let mut inputs = Inputs {
+            mode: FlightMode::Stabilise,
+            ..Inputs::default()
+        };
+        failsafe.update(&mut inputs, Instant::now(), 0.01);
+        assert_eq!(FlightMode::Off, inputs.mode);
+    }
+}
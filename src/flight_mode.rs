@@ -0,0 +1,160 @@
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+
+use crate::influx::{Log, Measurement};
+use crate::receiver::Inputs;
+
+/// What drives the actuators, selected by the pilot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlightMode {
+    /// actuators at trim
+    #[default]
+    Off,
+    /// sticks through the manual mix
+    Manual,
+    /// roll, pitch and yaw loops, the sonar is ignored
+    Stabilise,
+    /// all loops
+    AltitudeHold,
+    /// all loops while the autotune excites one axis after the other
+    Autotune,
+}
+
+impl FlightMode {
+    pub fn controller(self) -> bool {
+        matches!(
+            self,
+            FlightMode::Stabilise | FlightMode::AltitudeHold | FlightMode::Autotune
+        )
+    }
+
+    pub fn altitude_hold(self) -> bool {
+        matches!(self, FlightMode::AltitudeHold | FlightMode::Autotune)
+    }
+}
+
+/// Which signals are good enough to control on
+#[derive(Debug, Clone, Copy)]
+pub struct Guards {
+    pub attitude: bool,
+    pub altitude: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModeStatus {
+    pub mode: FlightMode,
+    pub requested: FlightMode,
+}
+
+impl Log for ModeStatus {
+    fn measurements(&self) -> Vec<Measurement> {
+        vec![
+            Measurement {
+                name: "mode",
+                value: self.mode as u8 as f32,
+            },
+            Measurement {
+                name: "requested",
+                value: self.requested as u8 as f32,
+            },
+        ]
+    }
+}
+
+/// Turns the requested mode into the active one.
+/// Without a good attitude the controller is off, without a good altitude it only stabilises the attitude.
+/// The autotune only starts from altitude hold, selected directly it holds the altitude until the pilot goes through altitude hold
+#[derive(Debug)]
+pub struct FlightModes {
+    mode: FlightMode,
+    requested: FlightMode,
+    pub status: Arc<Mutex<ModeStatus>>,
+}
+
+impl FlightModes {
+    pub fn new(status: Arc<Mutex<ModeStatus>>) -> Self {
+        Self {
+            mode: FlightMode::Off,
+            requested: FlightMode::Off,
+            status,
+        }
+    }
+
+    /// Replaces the requested mode in the inputs with the active one
+    pub fn update(&mut self, inputs: &mut Inputs, guards: Guards) -> FlightMode {
+        let requested = inputs.mode;
+        let autotune_allowed = self.mode == FlightMode::Autotune
+            || (self.mode == FlightMode::AltitudeHold
+                && self.requested == FlightMode::AltitudeHold);
+        let mode = match requested {
+            FlightMode::Off => FlightMode::Off,
+            FlightMode::Manual => FlightMode::Manual,
+            _ if !guards.attitude => FlightMode::Off,
+            FlightMode::Stabilise => FlightMode::Stabilise,
+            _ if !guards.altitude => FlightMode::Stabilise,
+            FlightMode::Autotune if autotune_allowed => FlightMode::Autotune,
+            _ => FlightMode::AltitudeHold,
+        };
+        if mode != self.mode {
+            println!("[FlightMode] {:?} -> {:?}", self.mode, mode);
+        }
+        self.mode = mode;
+        self.requested = requested;
+        *self.status.lock().unwrap() = ModeStatus { mode, requested };
+
+        inputs.mode = mode;
+        mode
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlightMode, FlightModes, Guards};
+    use crate::receiver::Inputs;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_transitions() {
+        let mut modes = FlightModes::new(Arc::default());
+        let good = Guards {
+            attitude: true,
+            altitude: true,
+        };
+        let mut step = |mode, guards| {
+            let mut inputs = Inputs {
+                mode,
+                ..Inputs::default()
+            };
+            modes.update(&mut inputs, guards)
+        };
+
+        // straight to autotune holds the altitude
+        assert_eq!(FlightMode::AltitudeHold, step(FlightMode::Autotune, good));
+        assert_eq!(FlightMode::AltitudeHold, step(FlightMode::Autotune, good));
+        assert_eq!(
+            FlightMode::AltitudeHold,
+            step(FlightMode::AltitudeHold, good)
+        );
+        assert_eq!(FlightMode::Autotune, step(FlightMode::Autotune, good));
+
+        // sonar lost
+        let no_altitude = Guards {
+            altitude: false,
+            ..good
+        };
+        assert_eq!(
+            FlightMode::Stabilise,
+            step(FlightMode::Autotune, no_altitude)
+        );
+        assert_eq!(FlightMode::AltitudeHold, step(FlightMode::Autotune, good));
+
+        // IMU lost, manual still works
+        let no_attitude = Guards {
+            attitude: false,
+            ..good
+        };
+        assert_eq!(FlightMode::Off, step(FlightMode::Stabilise, no_attitude));
+        assert_eq!(FlightMode::Manual, step(FlightMode::Manual, no_attitude));
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::control::{ControlAction, State};
+use crate::receiver::Inputs;
+
+/// The rudder servo drives the rudder through a 3:1 gear
+pub const RUDDER_GEAR_RATIO: f32 = 3.0;
+
+/// Something that can be driven to an angle, e.g. a servo
+pub trait Actuator {
+    /// Sets the angle in degrees relative to trim
+    fn set_angle(&mut self, angle_grad: f32);
+}
+
+/// Provides roll, pitch, yaw rate and pitch rate of the boat
+pub trait AttitudeSource {
+    /// Waits for the next sample and writes it into the measurement
+    fn poll(&mut self, measurement: &Mutex<State>);
+}
+
+/// Provides the ride height of the boat
+pub trait AltitudeSource {
+    /// Waits for the next sample and writes it into the measurement
+    fn poll(&mut self, measurement: &Mutex<State>);
+}
+
+/// Provides the pilot inputs
+pub trait RcSource {
+    /// Shared handle to the latest inputs, e.g. for logging
+    fn inputs(&self) -> Arc<Mutex<Inputs>>;
+
+    fn get_inputs(&self) -> Inputs {
+        *self.inputs().lock().unwrap()
+    }
+}
+
+/// The four actuators of the boat
+pub struct Actuators {
+    pub port: Box<dyn Actuator>,
+    pub starboard: Box<dyn Actuator>,
+    pub aft: Box<dyn Actuator>,
+    pub rudder: Box<dyn Actuator>,
+}
+
+impl Actuators {
+    pub fn apply(&mut self, action: &ControlAction) {
+        self.port.set_angle(action.port);
+        self.starboard.set_angle(action.starboard);
+        self.aft.set_angle(action.aft);
+        self.rudder.set_angle(action.rudder * RUDDER_GEAR_RATIO);
+    }
+}
+
+/// Polls an attitude source in its own thread.
+/// The source is created inside the thread, so it does not need to be `Send`
+pub fn spawn_attitude_source<S, F>(init: F, measurement: Arc<Mutex<State>>)
+where
+    S: AttitudeSource,
+    F: FnOnce() -> S + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut source = init();
+        loop {
+            source.poll(&measurement);
+        }
+    });
+}
+
+/// Polls an altitude source in its own thread.
+/// The source is created inside the thread, so it does not need to be `Send`
+pub fn spawn_altitude_source<S, F>(init: F, measurement: Arc<Mutex<State>>)
+where
+    S: AltitudeSource,
+    F: FnOnce() -> S + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut source = init();
+        loop {
+            source.poll(&measurement);
+        }
+    });
+}
@@ -5,6 +5,8 @@ use crate::influx::{Log, Measurement};
 pub struct RateRingBuffer {
     buffer: [Duration; 100],
     index: usize,
+    /// slots written so far, up to the length of the buffer
+    filled: usize,
 }
 
 impl RateRingBuffer {
@@ -12,22 +14,42 @@ impl RateRingBuffer {
         Self {
             buffer: [Duration::ZERO; 100],
             index: 0,
+            filled: 0,
         }
     }
 
     pub fn push(&mut self, duration: Duration) {
         self.buffer[self.index] = duration;
-        self.index = self.index % self.buffer.len();
+        self.index = (self.index + 1) % self.buffer.len();
+        self.filled = (self.filled + 1).min(self.buffer.len());
+    }
+
+    /// The loop durations pushed so far, 0 Hz before the first one
+    fn filled(&self) -> &[Duration] {
+        &self.buffer[..self.filled]
     }
 
     fn get_max_hz(&self) -> f64 {
-        1.0 / self.buffer.into_iter().max().unwrap().as_secs_f64()
+        match self.filled().iter().max() {
+            Some(max) => 1.0 / max.as_secs_f64(),
+            None => 0.0,
+        }
     }
-    fn get_average_hz(&self) -> f64 {
-        let sum: f64 = self.buffer.into_iter().map(|d| d.as_secs_f64()).sum();
-        self.buffer.len() as f64 / sum
+    pub fn get_average_hz(&self) -> f64 {
+        if self.filled == 0 {
+            return 0.0;
+        }
+        let sum: f64 = self.filled().iter().map(|d| d.as_secs_f64()).sum();
+        self.filled as f64 / sum
+    }
+}
+
+impl Default for RateRingBuffer {
+    fn default() -> Self {
+        Self::new()
     }
 }
+
 impl Log for RateRingBuffer {
     fn measurements(&self) -> Vec<Measurement> {
         vec![
@@ -42,3 +64,25 @@ impl Log for RateRingBuffer {
         ]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::RateRingBuffer;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate() {
+        let mut rate = RateRingBuffer::new();
+        assert_eq!(0.0, rate.get_average_hz());
+        rate.push(Duration::from_millis(10));
+        rate.push(Duration::from_millis(30));
+        assert!((rate.get_average_hz() - 50.0).abs() < 1e-9);
+
+        // the oldest durations are overwritten
+        for _ in 0..100 {
+            rate.push(Duration::from_millis(20));
+        }
+        assert!((rate.get_average_hz() - 50.0).abs() < 1e-9);
+        assert!((rate.get_max_hz() - 50.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,281 @@
+use serde::Deserialize;
+use std::thread;
+use std::time::Duration;
+
+use crate::bus::Bus;
+use crate::control::State;
+use crate::flight_mode::ModeStatus;
+use crate::helpers::RateRingBuffer;
+
+const DISCOVER: u8 = 0x80;
+const SENSOR_TYPE: u8 = 0x90;
+const MEASUREMENT: u8 = 0xA0;
+
+/// Sensor types the transmitter knows how to display
+const TYPE_EXTERNAL_VOLTAGE: u8 = 0x03;
+const TYPE_RPM: u8 = 0x07;
+const TYPE_ROLL: u8 = 0x0F;
+const TYPE_PITCH: u8 = 0x10;
+const TYPE_FLIGHT_MODE: u8 = 0x16;
+const TYPE_ALTITUDE: u8 = 0x83;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub enabled: bool,
+    /// UART wired to the sensor port of the receiver
+    pub port: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: String::from("/dev/ttyAMA3"),
+        }
+    }
+}
+
+/// The sensors in the order of their addresses, starting at 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sensor {
+    Altitude,
+    Roll,
+    Pitch,
+    FlightMode,
+    /// shown as RPM
+    LoopRate,
+    Battery,
+}
+
+const SENSORS: [Sensor; 6] = [
+    Sensor::Altitude,
+    Sensor::Roll,
+    Sensor::Pitch,
+    Sensor::FlightMode,
+    Sensor::LoopRate,
+    Sensor::Battery,
+];
+
+impl Sensor {
+    fn kind(self) -> u8 {
+        match self {
+            Sensor::Altitude => TYPE_ALTITUDE,
+            Sensor::Roll => TYPE_ROLL,
+            Sensor::Pitch => TYPE_PITCH,
+            Sensor::FlightMode => TYPE_FLIGHT_MODE,
+            Sensor::LoopRate => TYPE_RPM,
+            Sensor::Battery => TYPE_EXTERNAL_VOLTAGE,
+        }
+    }
+
+    /// Value in the units of the sensor type
+    fn encode(self, values: &TelemetryValues) -> Vec<u8> {
+        match self {
+            // cm
+            Sensor::Altitude => ((values.altitude * 100.0).round() as i32)
+                .to_le_bytes()
+                .to_vec(),
+            // 0.01 degrees
+            Sensor::Roll => ((values.roll * 100.0).round() as i16)
+                .to_le_bytes()
+                .to_vec(),
+            Sensor::Pitch => ((values.pitch * 100.0).round() as i16)
+                .to_le_bytes()
+                .to_vec(),
+            Sensor::FlightMode => (values.flight_mode as u16).to_le_bytes().to_vec(),
+            Sensor::LoopRate => (values.loop_rate.round() as u16).to_le_bytes().to_vec(),
+            // 0.01 V
+            Sensor::Battery => ((values.battery * 100.0).round() as u16)
+                .to_le_bytes()
+                .to_vec(),
+        }
+    }
+}
+
+/// What the pilot sees on the transmitter
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TelemetryValues {
+    /// meter
+    pub altitude: f32,
+    /// degrees
+    pub roll: f32,
+    pub pitch: f32,
+    pub flight_mode: u8,
+    /// Hz
+    pub loop_rate: f32,
+    /// volt
+    pub battery: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Request {
+    command: u8,
+    sensor: Sensor,
+}
+
+/// 0xFFFF minus the sum of all other bytes, little endian
+fn checksum(data: &[u8]) -> [u8; 2] {
+    let sum = data
+        .iter()
+        .fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+    (0xFFFF - sum).to_le_bytes()
+}
+
+fn frame(data: &[u8]) -> Vec<u8> {
+    let mut frame = vec![data.len() as u8 + 3];
+    frame.extend(data);
+    frame.extend(checksum(&frame));
+    frame
+}
+
+impl Request {
+    /// Answer of the addressed sensor
+    pub fn reply(&self, values: &TelemetryValues) -> Vec<u8> {
+        let address = SENSORS.iter().position(|s| *s == self.sensor).unwrap() as u8 + 1;
+        let header = self.command | address;
+        match self.command {
+            DISCOVER => frame(&[header]),
+            SENSOR_TYPE => {
+                let len = self.sensor.encode(values).len() as u8;
+                frame(&[header, self.sensor.kind(), len])
+            }
+            _ => {
+                let mut data = vec![header];
+                data.extend(self.sensor.encode(values));
+                frame(&data)
+            }
+        }
+    }
+}
+
+/// Parser for the 4 byte requests the receiver polls its sensor bus with:
+/// length, command and address, checksum.
+/// Requests for addresses without a sensor are left to other sensors on the bus
+#[derive(Debug, Default)]
+pub struct SensorBus {
+    frame: [u8; 4],
+    len: usize,
+}
+
+impl SensorBus {
+    /// Feeds one byte. Returns the request when it completes one for our sensors
+    pub fn push(&mut self, byte: u8) -> Option<Request> {
+        if self.len == 0 && byte != 0x04 {
+            return None;
+        }
+        self.frame[self.len] = byte;
+        self.len += 1;
+        if self.len < self.frame.len() {
+            return None;
+        }
+        self.len = 0;
+
+        let [_, header, _, _] = self.frame;
+        if checksum(&self.frame[..2]) != self.frame[2..] {
+            // the next request may already have started within this one
+            if let Some(i) = self.frame[1..].iter().position(|b| *b == 0x04) {
+                let rest = self.frame.len() - 1 - i;
+                self.frame.copy_within(1 + i.., 0);
+                self.len = rest;
+            }
+            return None;
+        }
+        let command = header & 0xF0;
+        let address = (header & 0x0F) as usize;
+        if ![DISCOVER, SENSOR_TYPE, MEASUREMENT].contains(&command) {
+            return None;
+        }
+        let sensor = *SENSORS.get(address.checked_sub(1)?)?;
+        Some(Request { command, sensor })
+    }
+}
+
+/// Answers the receiver's sensor bus with the values on the bus.
+/// The battery voltage is read from "battery_voltage", 0 unless the battery reader is enabled
+pub fn spawn_telemetry(config: &TelemetryConfig, bus: &Bus) {
+    let estimate = bus.latest::<State>("estimate");
+    let measurement = bus.latest::<State>("measurement");
+    let flight_mode = bus.latest::<ModeStatus>("flight_mode");
+    let rate = bus.latest::<RateRingBuffer>("pid_rate");
+    let battery = bus.latest::<f32>("battery_voltage");
+
+    let mut port = serialport::new(&config.port, 115_200)
+        .timeout(Duration::from_millis(100))
+        .open()
+        .expect("Failed to open telemetry port");
+
+    thread::spawn(move || {
+        let mut sensors = SensorBus::default();
+        let mut buffer = [0u8; 16];
+        loop {
+            let n = port.read(&mut buffer).unwrap_or(0);
+            for byte in &buffer[..n] {
+                let Some(request) = sensors.push(*byte) else {
+                    continue;
+                };
+                let attitude = *measurement.lock().unwrap();
+                let values = TelemetryValues {
+                    altitude: estimate.lock().unwrap().altitude,
+                    roll: attitude.roll,
+                    pitch: attitude.pitch,
+                    flight_mode: flight_mode.lock().unwrap().mode as u8,
+                    loop_rate: rate.lock().unwrap().get_average_hz() as f32,
+                    battery: *battery.lock().unwrap(),
+                };
+                let reply = request.reply(&values);
+                if port.write_all(&reply).is_err() {
+                    continue;
+                }
+                // the sensor bus is a single wire, the reply comes back as an echo
+                let mut echo = vec![0u8; reply.len()];
+                let _ = port.read_exact(&mut echo);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SensorBus, TelemetryValues};
+
+    #[test]
+    fn test_sensor_bus() {
+        let mut sensors = SensorBus::default();
+        let values = TelemetryValues {
+            altitude: 0.42,
+            roll: -5.0,
+            battery: 12.34,
+            ..TelemetryValues::default()
+        };
+        let mut poll = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .filter_map(|b| sensors.push(*b))
+                .map(|request| request.reply(&values))
+                .collect::<Vec<_>>()
+        };
+
+        // discover address 1, a bad checksum, address 7 belongs to someone else
+        let replies = poll(&[
+            0x04, 0x81, 0x7A, 0xFF, 0x04, 0x81, 0x00, 0x00, 0x04, 0x87, 0x74, 0xFF,
+        ]);
+        assert_eq!(vec![vec![0x04, 0x81, 0x7A, 0xFF]], replies);
+
+        // type and value of the altitude, 42 cm
+        let replies = poll(&[0x04, 0x91, 0x6A, 0xFF, 0x04, 0xA1, 0x5A, 0xFF]);
+        assert_eq!(vec![0x06, 0x91, 0x83, 0x04, 0xE1, 0xFE], replies[0]);
+        assert_eq!(
+            vec![0x08, 0xA1, 42, 0x00, 0x00, 0x00, 0x2C, 0xFF],
+            replies[1]
+        );
+
+        // roll in 0.01 degrees
+        let replies = poll(&[0x04, 0xA2, 0x59, 0xFF]);
+        assert_eq!(&(-500i16).to_le_bytes(), &replies[0][2..4]);
+
+        // battery in 0.01 V at the last address
+        let replies = poll(&[0x04, 0xA6, 0x55, 0xFF]);
+        assert_eq!(&1234u16.to_le_bytes(), &replies[0][2..4]);
+    }
+}
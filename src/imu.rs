@@ -1,88 +1,466 @@
 use bno085::{
-    bno_constants::{SENSOR_REPORTID_GYRO_CALIBRATED, SENSOR_REPORTID_ROTATION_VECTOR},
-    bno_driver::BnoDriver,
+    bno_constants::{
+        SENSOR_REPORTID_GRAVITY, SENSOR_REPORTID_GYRO_CALIBRATED,
+        SENSOR_REPORTID_LINEAR_ACCELERATION, SENSOR_REPORTID_ROTATION_VECTOR,
+    },
+    bno_driver::{BnoDriver, DriverError},
     bno_packet::{BnoPacket, ChannelExecutableData, SensorReportData},
     interface::i2c::I2CInterface,
 };
+use rppal::i2c::I2c;
 use std::{
     f32::consts::PI,
-    sync::{Arc, Mutex},
+    sync::{mpsc::Receiver, Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use nalgebra::geometry::{Quaternion, UnitQuaternion};
+use nalgebra::Vector3;
+use serde::Deserialize;
 
+use crate::bus::Bus;
+use crate::calibration::{CalibrationConfig, LevelCalibration, LevelOffset};
 use crate::control::State;
+use crate::hal::AttitudeSource;
+use crate::influx::{Log, Measurement};
 
-#[derive(Clone, Copy)]
-struct Attitude {
-    roll: f32,
-    pitch: f32,
+/// Orientation of the sensor in the boat, rotates sensor axes into boat axes
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Mounting {
+    /// in degrees
+    Euler {
+        roll: f32,
+        pitch: f32,
+        yaw: f32,
+    },
+    Quaternion {
+        w: f32,
+        x: f32,
+        y: f32,
+        z: f32,
+    },
 }
 
-pub fn handle_imu(measurement: Arc<Mutex<State>>) {
-    let rpi_interface = rppal::i2c::I2c::new().unwrap();
-    let interface = I2CInterface::new(rpi_interface);
+impl Default for Mounting {
+    fn default() -> Self {
+        Mounting::Euler {
+            roll: 0.0,
+            pitch: 0.0,
+            yaw: 0.0,
+        }
+    }
+}
 
-    let interval = 16;
+impl Mounting {
+    fn rotation(&self) -> UnitQuaternion<f32> {
+        match *self {
+            Mounting::Euler { roll, pitch, yaw } => UnitQuaternion::from_euler_angles(
+                roll.to_radians(),
+                pitch.to_radians(),
+                yaw.to_radians(),
+            ),
+            Mounting::Quaternion { w, x, y, z } => {
+                UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z))
+            }
+        }
+    }
+}
+
+/// Intervals of the BNO085 reports in ms, 0 disables a report
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct Reports {
+    pub rotation: u16,
+    pub gyro: u16,
+    pub linear_acceleration: u16,
+    pub gravity: u16,
+}
+
+impl Default for Reports {
+    fn default() -> Self {
+        Self {
+            rotation: 16,
+            gyro: 16,
+            linear_acceleration: 16,
+            gravity: 100,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ImuConfig {
+    pub mounting: Mounting,
+    pub calibration: CalibrationConfig,
+    pub reports: Reports,
+    /// the sensor is reset if no report arrives for this long
+    pub watchdog_ms: u64,
+    /// a reset that does not complete within this is sent again
+    pub reset_timeout_ms: u64,
+}
+
+impl Default for ImuConfig {
+    fn default() -> Self {
+        Self {
+            mounting: Mounting::default(),
+            calibration: CalibrationConfig::default(),
+            reports: Reports::default(),
+            watchdog_ms: 200,
+            reset_timeout_ms: 1000,
+        }
+    }
+}
+
+/// Watchdog state of the IMU, for telemetry and the failsafe
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImuHealth {
+    /// reports arrive in time
+    pub healthy: bool,
+    /// a level offset was loaded or calibrated, without it the attitude is off by the mounting tolerances
+    pub levelled: bool,
+    /// soft resets the driver could not send
+    pub reset_failures: u32,
+    /// reports the driver could not enable after a reset
+    pub enable_failures: u32,
+    /// driver errors while receiving other than no data
+    pub receive_other: u32,
+    /// resets by the watchdog after missing reports
+    pub resets: u32,
+    /// resets the sensor did on its own
+    pub unexpected_resets: u32,
+}
+
+impl ImuHealth {
+    /// The attitude can be controlled on
+    pub fn ready(&self) -> bool {
+        self.healthy && self.levelled
+    }
+
+    /// Counts a receive error in the counter of its variant
+    fn count_receive_error(&mut self, error: DriverError) {
+        match error {
+            // can happen due to sleep/clock drift
+            DriverError::NoDataAvailable => {}
+            DriverError::Other => self.receive_other += 1,
+        }
+    }
+}
 
-    let mut driver = BnoDriver::new(interface);
-    driver.setup();
-    driver.soft_reset().unwrap();
+impl Log for ImuHealth {
+    fn measurements(&self) -> Vec<Measurement> {
+        vec![
+            Measurement {
+                name: "healthy",
+                value: self.healthy as u8 as f32,
+            },
+            Measurement {
+                name: "levelled",
+                value: self.levelled as u8 as f32,
+            },
+            Measurement {
+                name: "reset_failures",
+                value: self.reset_failures as f32,
+            },
+            Measurement {
+                name: "enable_failures",
+                value: self.enable_failures as f32,
+            },
+            Measurement {
+                name: "resets",
+                value: self.resets as f32,
+            },
+            Measurement {
+                name: "receive_other",
+                value: self.receive_other as f32,
+            },
+            Measurement {
+                name: "resets",
+                value: self.resets as f32,
+            },
+            Measurement {
+                name: "unexpected_resets",
+                value: self.unexpected_resets as f32,
+            },
+        ]
+    }
+}
 
-    let mut offset: Option<Attitude> = None;
+/// Everything the IMU measures, in boat axes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImuState {
+    /// degrees
+    pub heading: f32,
+    /// degrees per second
+    pub rates: [f32; 3],
+    /// m/s² without gravity
+    pub linear_acceleration: [f32; 3],
+    /// m/s²
+    pub gravity: [f32; 3],
+}
 
-    loop {
-        match driver.receive_packet() {
+impl Log for ImuState {
+    fn measurements(&self) -> Vec<Measurement> {
+        vec![
+            Measurement {
+                name: "heading",
+                value: self.heading,
+            },
+            Measurement {
+                name: "roll_rate",
+                value: self.rates[0],
+            },
+            Measurement {
+                name: "pitch_rate",
+                value: self.rates[1],
+            },
+            Measurement {
+                name: "yaw_rate",
+                value: self.rates[2],
+            },
+            Measurement {
+                name: "acceleration_x",
+                value: self.linear_acceleration[0],
+            },
+            Measurement {
+                name: "acceleration_y",
+                value: self.linear_acceleration[1],
+            },
+            Measurement {
+                name: "acceleration_z",
+                value: self.linear_acceleration[2],
+            },
+            Measurement {
+                name: "gravity_x",
+                value: self.gravity[0],
+            },
+            Measurement {
+                name: "gravity_y",
+                value: self.gravity[1],
+            },
+            Measurement {
+                name: "gravity_z",
+                value: self.gravity[2],
+            },
+        ]
+    }
+}
+
+/// BNO085 on the Pi's I2C bus.
+/// Publishes "imu" and "imu_health", a level calibration is started by publishing on "imu/calibrate"
+pub struct Imu {
+    driver: BnoDriver<I2CInterface<I2c>>,
+    reports: Reports,
+    offset: LevelOffset,
+    calibration_config: CalibrationConfig,
+    calibration: Option<LevelCalibration>,
+    calibration_requests: Receiver<()>,
+    /// a level calibration starts with the next attitude sample
+    calibration_requested: bool,
+    /// sensor to boat
+    mounting: UnitQuaternion<f32>,
+    /// latest orientation of the boat
+    orientation: UnitQuaternion<f32>,
+    state: Arc<Mutex<ImuState>>,
+    watchdog: Duration,
+    reset_timeout: Duration,
+    /// last report, or when the last reset was sent or completed
+    last_report: Instant,
+    /// a reset was requested and has not completed yet, the watchdog waits for the reset timeout
+    resetting: bool,
+    health: Arc<Mutex<ImuHealth>>,
+}
+
+impl Imu {
+    pub fn new(config: ImuConfig, bus: &Bus) -> Self {
+        let rpi_interface = I2c::new().unwrap();
+        let interface = I2CInterface::new(rpi_interface);
+
+        let mut driver = BnoDriver::new(interface);
+        driver.setup();
+        driver.soft_reset().unwrap();
+
+        let offset = LevelOffset::load(&config.calibration.offset_path);
+        if offset.is_none() {
+            println!("[Imu] no level offset, calibrating. Keep the boat still");
+        }
+        let health = bus.logged::<ImuHealth>("imu_health");
+        health.lock().unwrap().levelled = offset.is_some();
+
+        Self {
+            driver,
+            reports: config.reports,
+            offset: offset.unwrap_or_default(),
+            calibration_config: config.calibration,
+            calibration: None,
+            calibration_requests: bus.queue("imu/calibrate").subscribe(),
+            calibration_requested: offset.is_none(),
+            mounting: config.mounting.rotation(),
+            orientation: UnitQuaternion::identity(),
+            state: bus.logged("imu"),
+            watchdog: Duration::from_millis(config.watchdog_ms),
+            reset_timeout: Duration::from_millis(config.reset_timeout_ms),
+            last_report: Instant::now(),
+            resetting: true,
+            health,
+        }
+    }
+
+    /// Resets the sensor if the reports stopped, the reports are enabled again when the reset completes.
+    /// A reset that does not complete is sent again
+    fn check_watchdog(&mut self) {
+        if self.resetting {
+            if self.last_report.elapsed() < self.reset_timeout {
+                return;
+            }
+            println!("[Imu] reset did not complete, resetting again");
+        } else {
+            if self.last_report.elapsed() < self.watchdog {
+                return;
+            }
+            println!("[Imu] no reports, resetting");
+        }
+        {
+            let mut health = self.health.lock().unwrap();
+            health.healthy = false;
+            health.resets += 1;
+        }
+        if self.driver.soft_reset().is_err() {
+            self.health.lock().unwrap().reset_failures += 1;
+        }
+        self.resetting = true;
+        self.last_report = Instant::now();
+    }
+
+    fn enable_reports(&mut self) {
+        let reports = [
+            (SENSOR_REPORTID_ROTATION_VECTOR, self.reports.rotation),
+            (SENSOR_REPORTID_GYRO_CALIBRATED, self.reports.gyro),
+            (
+                SENSOR_REPORTID_LINEAR_ACCELERATION,
+                self.reports.linear_acceleration,
+            ),
+            (SENSOR_REPORTID_GRAVITY, self.reports.gravity),
+        ];
+        for (report, interval) in reports {
+            // a failed report shows up as missing reports and is retried by the watchdog
+            if interval > 0
+                && self
+                    .driver
+                    .enable_report(report, interval, interval - 1)
+                    .is_err()
+            {
+                self.health.lock().unwrap().enable_failures += 1;
+            }
+        }
+    }
+
+    pub fn request_calibration(&mut self) {
+        self.calibration_requested = true;
+    }
+
+    /// While a level calibration is requested or running
+    pub fn calibrating(&self) -> bool {
+        self.calibration.is_some() || self.calibration_requested
+    }
+
+    /// Runs the level calibration on an uncorrected attitude sample
+    fn calibrate(&mut self, roll: f32, pitch: f32) {
+        self.calibration_requested |= self.calibration_requests.try_iter().count() > 0;
+        if std::mem::take(&mut self.calibration_requested) {
+            self.calibration = Some(LevelCalibration::new(&self.calibration_config));
+        }
+        let Some(calibration) = &mut self.calibration else {
+            return;
+        };
+        if let Some(offset) = calibration.push(roll, pitch, self.reports.rotation as f32 / 1000.0) {
+            println!(
+                "[Imu] level offset roll: {} pitch: {}",
+                offset.roll, offset.pitch
+            );
+            offset.save(&self.calibration_config.offset_path);
+            self.offset = offset;
+            self.calibration = None;
+            self.health.lock().unwrap().levelled = true;
+        }
+    }
+}
+
+/// Rotates a vector measured in sensor axes, e.g. the rates, into boat axes
+fn to_boat(mounting: &UnitQuaternion<f32>, values: &[f32; 3]) -> Vector3<f32> {
+    mounting * Vector3::new(values[0], values[1], values[2])
+}
+
+/// Orientation of the boat from the rotation vector of the sensor, i, j, k and real part
+fn boat_orientation(mounting: &UnitQuaternion<f32>, values: &[f32; 4]) -> UnitQuaternion<f32> {
+    let sensor = UnitQuaternion::from_quaternion(Quaternion::new(
+        values[3], values[0], values[1], values[2],
+    ));
+    sensor * mounting.inverse()
+}
+
+impl AttitudeSource for Imu {
+    fn poll(&mut self, measurement: &Mutex<State>) {
+        self.check_watchdog();
+
+        match self.driver.receive_packet() {
             Ok(packet) => match packet {
                 BnoPacket::ChannelExec(ce) => match ce {
                     ChannelExecutableData::ResetComplete => {
                         print!("Reset Complete, enabling Reports!");
+                        if !self.resetting {
+                            self.health.lock().unwrap().unexpected_resets += 1;
+                        }
+                        self.resetting = false;
+                        // the watchdog waits for the first reports from here
+                        self.last_report = Instant::now();
                         // Enable reports after reset
-                        driver
-                            .enable_report(SENSOR_REPORTID_ROTATION_VECTOR, interval, interval - 1)
-                            .unwrap();
-                        driver
-                            .enable_report(SENSOR_REPORTID_GYRO_CALIBRATED, interval, interval - 1)
-                            .unwrap();
+                        self.enable_reports();
                     }
                     ChannelExecutableData::Unknown(_ced) => {
                         //println!("CED {:?}", ced);
                     }
                 },
                 BnoPacket::SensorReports(reports) => {
+                    self.last_report = Instant::now();
+                    self.health.lock().unwrap().healthy = true;
                     for report in reports {
                         match report {
                             SensorReportData::Rotation(d) => {
-                                let euler_angles_rad =
-                                    UnitQuaternion::from_quaternion(Quaternion::new(
-                                        d.values[3],
-                                        d.values[0],
-                                        d.values[1],
-                                        d.values[2],
-                                    ))
-                                    .euler_angles();
+                                self.orientation = boat_orientation(&self.mounting, &d.values);
+                                let euler_angles_rad = self.orientation.euler_angles();
                                 let euler_angles = (
                                     euler_angles_rad.0 / PI * 180.0,
                                     euler_angles_rad.1 / PI * 180.0,
                                     euler_angles_rad.2 / PI * 180.0,
                                 );
-                                match offset {
-                                    None => {
-                                        offset = Some(Attitude {
-                                            roll: euler_angles.0,
-                                            pitch: euler_angles.1,
-                                        })
-                                    }
-                                    Some(offset) => {
-                                        let mut unlocked = measurement.lock().unwrap();
-                                        unlocked.roll = euler_angles.0 - offset.roll;
-                                        unlocked.pitch = euler_angles.1 - offset.pitch;
-                                    }
-                                };
-                                {}
+                                self.calibrate(euler_angles.0, euler_angles.1);
+                                self.state.lock().unwrap().heading = euler_angles.2;
+                                let mut unlocked = measurement.lock().unwrap();
+                                unlocked.roll = euler_angles.0 - self.offset.roll;
+                                unlocked.pitch = euler_angles.1 - self.offset.pitch;
+                                unlocked.sampled.attitude = Some(Instant::now());
                             }
                             SensorReportData::GyroCalibrated(d) => {
-                                measurement.lock().unwrap().yaw_rate = d.values[2] / PI * 180.0;
+                                let rates = to_boat(&self.mounting, &d.values) / PI * 180.0;
+                                self.state.lock().unwrap().rates = rates.into();
+                                let mut unlocked = measurement.lock().unwrap();
+                                unlocked.pitch_rate = rates.y;
+                                unlocked.yaw_rate = rates.z;
+                                unlocked.sampled.rates = Some(Instant::now());
+                            }
+                            SensorReportData::LinearAcceleration(d) => {
+                                let acceleration = to_boat(&self.mounting, &d.values);
+                                self.state.lock().unwrap().linear_acceleration =
+                                    acceleration.into();
+                                let world = self.orientation * acceleration;
+                                let mut unlocked = measurement.lock().unwrap();
+                                unlocked.vertical_acceleration = world.z;
+                                unlocked.sampled.acceleration = Some(Instant::now());
+                            }
+                            SensorReportData::Gravity(d) => {
+                                self.state.lock().unwrap().gravity =
+                                    to_boat(&self.mounting, &d.values).into();
                             }
                             d => {
                                 print!("Unknown Sensor Data {:?}", d);
@@ -95,14 +473,48 @@ pub fn handle_imu(measurement: Arc<Mutex<State>>) {
                 }
             },
             Err(err) => {
-                match err {
-                    bno085::bno_driver::DriverError::NoDataAvailable => { /* Nothing to do, can happen due to sleep/clock drift */
-                    }
-                    _e => {
-                        //print!("BNO Driver Error {:?}", e);
-                    }
-                }
+                self.health.lock().unwrap().count_receive_error(err);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{boat_orientation, to_boat, DriverError, ImuHealth, Mounting};
+    use nalgebra::{UnitQuaternion, Vector3};
+
+    #[test]
+    fn test_mounting() {
+        // upside down and turned to starboard: sensor x along boat y, sensor y along boat x, sensor z up
+        let mounting = Mounting::Euler {
+            roll: 180.0,
+            pitch: 0.0,
+            yaw: 90.0,
+        }
+        .rotation();
+
+        // rolling, pitching and yawing boat, the sensor sees the rates on its own axes
+        let rates = to_boat(&mounting, &[2.0, 1.0, -3.0]);
+        assert!((rates - Vector3::new(1.0, 2.0, 3.0)).norm() < 1e-5);
+
+        // heeled and bow up, the sensor reports its own orientation
+        let (roll, pitch, yaw) = (5f32.to_radians(), 10f32.to_radians(), 30f32.to_radians());
+        let boat = UnitQuaternion::from_euler_angles(roll, pitch, yaw);
+        let sensor = (boat * mounting).into_inner();
+        let orientation = boat_orientation(&mounting, &[sensor.i, sensor.j, sensor.k, sensor.w]);
+        let (r, p, y) = orientation.euler_angles();
+        assert!((r - roll).abs() < 1e-5);
+        assert!((p - pitch).abs() < 1e-5);
+        assert!((y - yaw).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_receive_errors() {
+        let mut health = ImuHealth::default();
+        health.count_receive_error(DriverError::NoDataAvailable);
+        health.count_receive_error(DriverError::Other);
+        health.count_receive_error(DriverError::Other);
+        assert_eq!(2, health.receive_other);
+    }
+}
@@ -27,7 +27,7 @@ pub trait Log: Send + Sync + 'static {
     }
 }
 
-pub fn influx_log<T: Log>(shared: Arc<Mutex<T>>, measurement: String, interval: Duration) {
+pub fn influx_log<T: Log + ?Sized>(shared: Arc<Mutex<T>>, measurement: String, interval: Duration) {
     let influx_url = env::var("INFLUX_URL").expect("no url provided");
     let influx_bucket = env::var("INFLUX_BUCKET").expect("no bucket provided");
     let influx_token = env::var("INFLUX_TOKEN").expect("no token provided");
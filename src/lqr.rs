@@ -0,0 +1,215 @@
+use nalgebra::{DMatrix, DVector, SMatrix, SVector};
+use serde::Deserialize;
+
+use crate::control::{ControlAction, Controller, State, ALTITUDE_FADE_S};
+use crate::mixer::ActuatorLimits;
+
+/// Signals the controller acts on, in this order: roll, pitch, yaw rate, altitude, pitch rate
+const SIGNALS: usize = 5;
+
+#[derive(Deserialize)]
+struct LqrConfig {
+    k: Vec<Vec<f32>>,
+    #[serde(default)]
+    a: Vec<Vec<f32>>,
+    #[serde(default)]
+    b: Vec<Vec<f32>>,
+    #[serde(default)]
+    c: Vec<Vec<f32>>,
+}
+
+/// Discrete state-space controller running at the control rate.
+/// With e = setpoint - measurement and the controller states x:
+///
+///   u = K e + C x
+///   x[k+1] = A x[k] + B e[k]
+///
+/// u are the actuator angles in degrees (port, starboard, aft, rudder).
+/// Without A, B and C this is a static LQR state feedback: u = -K x with x = measurement - setpoint.
+/// The states are not updated while an actuator is saturated
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "LqrConfig")]
+pub struct LqrController {
+    k: SMatrix<f32, 4, SIGNALS>,
+    a: DMatrix<f32>,
+    b: DMatrix<f32>,
+    c: DMatrix<f32>,
+    x: DVector<f32>,
+    /// the altitude error is ignored
+    attitude_only: bool,
+    /// share of the altitude error acted on, ramps between 0 and 1 when the altitude hold is switched
+    altitude_weight: f32,
+    pub limits: ActuatorLimits,
+}
+
+fn matrix(name: &str, rows: &[Vec<f32>], shape: (usize, usize)) -> Result<DMatrix<f32>, String> {
+    if rows.len() != shape.0 || rows.iter().any(|row| row.len() != shape.1) {
+        return Err(format!(
+            "lqr: {} must be a {}x{} matrix",
+            name, shape.0, shape.1
+        ));
+    }
+    Ok(DMatrix::from_row_iterator(
+        shape.0,
+        shape.1,
+        rows.iter().flatten().copied(),
+    ))
+}
+
+impl TryFrom<LqrConfig> for LqrController {
+    type Error = String;
+
+    fn try_from(config: LqrConfig) -> Result<Self, Self::Error> {
+        let k = matrix("k", &config.k, (4, SIGNALS))?;
+        let n = config.a.len();
+        let (a, b, c) = if n == 0 {
+            // static state feedback
+            (
+                DMatrix::zeros(0, 0),
+                DMatrix::zeros(0, SIGNALS),
+                DMatrix::zeros(4, 0),
+            )
+        } else {
+            (
+                matrix("a", &config.a, (n, n))?,
+                matrix("b", &config.b, (n, SIGNALS))?,
+                matrix("c", &config.c, (4, n))?,
+            )
+        };
+        Ok(Self {
+            k: k.fixed_view::<4, SIGNALS>(0, 0).into(),
+            a,
+            b,
+            c,
+            x: DVector::zeros(n),
+            attitude_only: false,
+            altitude_weight: 1.0,
+            limits: ActuatorLimits::default(),
+        })
+    }
+}
+
+fn error(setpoint: State, measurement: State) -> SVector<f32, SIGNALS> {
+    SVector::from([
+        setpoint.roll - measurement.roll,
+        setpoint.pitch - measurement.pitch,
+        setpoint.yaw_rate - measurement.yaw_rate,
+        setpoint.altitude - measurement.altitude,
+        setpoint.pitch_rate - measurement.pitch_rate,
+    ])
+}
+
+impl LqrController {
+    fn altitude_target(&self) -> f32 {
+        if self.attitude_only {
+            0.0
+        } else {
+            1.0
+        }
+    }
+
+    fn weighted_error(&self, setpoint: State, measurement: State) -> SVector<f32, SIGNALS> {
+        let mut e = error(setpoint, measurement);
+        e[3] *= self.altitude_weight;
+        e
+    }
+}
+
+impl Controller for LqrController {
+    fn update(&mut self, setpoint: State, measurement: State, dt: f32) -> ControlAction {
+        let step = dt / ALTITUDE_FADE_S;
+        self.altitude_weight += (self.altitude_target() - self.altitude_weight).clamp(-step, step);
+        let e = self.weighted_error(setpoint, measurement);
+        let u = self.k * e + &self.c * &self.x;
+
+        let action = ControlAction::from([u[0], u[1], u[2], u[3]]);
+        let clamped = self.limits.clamp(action);
+        if clamped == action {
+            self.x = &self.a * &self.x + &self.b * e;
+        }
+        clamped
+    }
+
+    fn reset(&mut self) {
+        self.x.fill(0.0);
+    }
+
+    fn set_altitude_hold(&mut self, enabled: bool) {
+        self.attitude_only = !enabled;
+    }
+
+    /// Least squares fit of the controller states to the current action
+    fn initialise(&mut self, setpoint: State, measurement: State, action: ControlAction) {
+        self.reset();
+        self.altitude_weight = self.altitude_target();
+        if self.x.is_empty() {
+            return;
+        }
+        let action: [f32; 4] = action.into();
+        let remainder = SVector::from(action) - self.k * self.weighted_error(setpoint, measurement);
+        if let Ok(inverse) = self.c.clone().pseudo_inverse(1e-6) {
+            self.x = inverse * remainder;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LqrController;
+    use crate::control::{Controller, State};
+
+    #[test]
+    fn test_static_gain() {
+        let mut lqr: LqrController = serde_yaml::from_str(
+            "
+k:
+  - [ 1.0, 0.0, 0.0, -20.0, 0.0]
+  - [-1.0, 0.0, 0.0, -20.0, 0.0]
+  - [ 0.0, 1.0, 0.0, -10.0, 0.5]
+  - [ 0.0, 0.0, 1.0,   0.0, 0.0]
+",
+        )
+        .unwrap();
+
+        let setpoint = State {
+            roll: 2.0,
+            ..State::default()
+        };
+        let action = lqr.update(setpoint, State::default(), 0.01);
+        assert_eq!(2.0, action.port);
+        assert_eq!(-2.0, action.starboard);
+
+        // saturates at the actuator limits
+        let setpoint = State {
+            altitude: 1.0,
+            ..State::default()
+        };
+        assert_eq!(-13.0, lqr.update(setpoint, State::default(), 0.01).port);
+    }
+
+    #[test]
+    fn test_integral_state() {
+        let mut lqr: LqrController = serde_yaml::from_str(
+            "
+k: [[0, 0, 0, 0, 0], [0, 0, 0, 0, 0], [0, 0, 0, 0, 0], [0, 0, 0, 0, 0]]
+a: [[1.0]]
+b: [[0.0, 0.0, 0.0, 0.01, 0.0]]
+c: [[-1.0], [-1.0], [-1.0], [0.0]]
+",
+        )
+        .unwrap();
+
+        let setpoint = State {
+            altitude: 1.0,
+            ..State::default()
+        };
+        lqr.update(setpoint, State::default(), 0.01);
+        let action = lqr.update(setpoint, State::default(), 0.01);
+        assert!((action.aft - -0.01).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_invalid_shape() {
+        assert!(serde_yaml::from_str::<LqrController>("k: [[1.0, 0.0]]").is_err());
+    }
+}
@@ -1,31 +1,97 @@
+mod autotune;
+mod battery;
+mod bus;
+mod calibration;
+mod channels;
 mod control;
+mod crsf;
+mod engage;
+mod estimator;
+mod failsafe;
+mod flight_mode;
+mod hal;
 mod helpers;
+mod ibus_telemetry;
 mod imu;
 mod influx;
+mod lqr;
+mod mixer;
+mod mock;
 mod receiver;
+mod sbus;
+mod schedule;
 mod servo;
+mod sim;
 mod sonar;
+mod sonar_filter;
+mod sonar_frame;
+mod staleness;
 
-use control::{ControlAction, FlightController, State};
+use battery::{spawn_battery, BatteryConfig};
+use bus::Bus;
+use control::{ControlAction, Controller, ControllerConfig, State};
+use engage::Engagement;
+use estimator::{AltitudeEstimator, EstimatorConfig};
+use failsafe::Failsafe;
+use flight_mode::{FlightMode, FlightModes, Guards};
+use hal::{
+    spawn_altitude_source, spawn_attitude_source, Actuators, AttitudeSource, RcSource,
+    RUDDER_GEAR_RATIO,
+};
 use helpers::RateRingBuffer;
-use imu::handle_imu;
-use influx::influx_log;
-use receiver::Receiver;
+use ibus_telemetry::{spawn_telemetry, TelemetryConfig};
+use imu::{Imu, ImuConfig, ImuHealth};
+use mixer::ActuatorLimits;
+use mock::{MockActuator, MockAltitude, MockAttitude, MockRc};
+use receiver::{Inputs, Receiver};
 use serde::Deserialize;
 use servo::Servo;
-use sonar::handle_sonar;
+use sim::SimConfig;
+use sonar::{Sonar, SonarMount};
+use sonar_filter::{SonarFilterConfig, SonarStatus};
+use staleness::{MaxAge, SignalAges};
 
 use std::env;
-use std::sync::{Arc, Mutex};
-use std::thread::{self, sleep};
-use std::time::{Duration, SystemTime};
+use std::process::exit;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+const CONTROL_RATE: Duration = Duration::from_millis(10);
 
 #[derive(Deserialize)]
 struct Configuration {
-    controller: FlightController,
+    controller: ControllerConfig,
     receiver: Receiver,
     trim: ControlAction,
+    #[serde(default)]
+    actuator_limits: ActuatorLimits,
+    #[serde(default)]
+    altitude_estimator: EstimatorConfig,
+    #[serde(default)]
+    imu: ImuConfig,
+    #[serde(default)]
+    sonar_filter: SonarFilterConfig,
+    #[serde(default)]
+    sonar_mount: SonarMount,
     logging_interval_ms: u64,
+    /// values shown on the transmitter
+    #[serde(default)]
+    telemetry: TelemetryConfig,
+    /// voltage shown on the transmitter
+    #[serde(default)]
+    battery: BatteryConfig,
+    /// the controller is only engaged while all signals are fresh
+    #[serde(default)]
+    max_age_ms: MaxAge,
+    /// time to return the actuators to trim when the controller is disengaged
+    #[serde(default)]
+    disengage_ramp_s: f32,
+    /// sticks (roll, pitch, yaw, altitude) to actuator angles in manual mode
+    #[serde(default)]
+    manual_mix: [[f32; 4]; 4],
+    #[serde(default)]
+    sim: SimConfig,
 }
 
 fn main() -> () {
@@ -39,92 +105,165 @@ fn main() -> () {
 
     let config: Configuration = serde_yaml::from_str(&yaml_str).unwrap();
 
-    let mut controller: FlightController = config.controller;
+    // HARDWARE=mock runs the flight stack without a Raspberry Pi
+    let mock_hardware = env::var("HARDWARE").is_ok_and(|hardware| hardware == "mock");
+
+    let mut controller = config.controller.build(config.actuator_limits);
+
+    // `auklet calibrate` levels the IMU and exits, the boat has to be still
+    if env::args().nth(1).is_some_and(|mode| mode == "calibrate") {
+        // the calibration averages the rotation vector, without it this would wait forever
+        if config.imu.reports.rotation == 0 {
+            eprintln!("[Imu] the rotation report is disabled, cannot calibrate");
+            exit(1);
+        }
+        let mut imu = Imu::new(config.imu, &Bus::new(None));
+        imu.request_calibration();
+        let measurement = Mutex::new(State::default());
+        while imu.calibrating() {
+            imu.poll(&measurement);
+        }
+        exit(0);
+    }
+
+    // `auklet sim` runs the controller against a simulated boat instead of the hardware
+    if env::args().nth(1).is_some_and(|mode| mode == "sim") {
+        let stable = sim::run(
+            controller.as_mut(),
+            config.receiver.default_setpoint,
+            &config.actuator_limits,
+            config.altitude_estimator,
+            &config.sim,
+            CONTROL_RATE.as_secs_f32(),
+            Duration::from_millis(config.logging_interval_ms).as_secs_f32(),
+        );
+        exit(if stable { 0 } else { 1 });
+    }
+
+    // every shared value is a topic on the bus, logged ones are sent to influx under the topic name
+    let bus = Bus::new(Some(Duration::from_millis(config.logging_interval_ms)));
 
     let receiver: Receiver = config.receiver;
-    receiver.run();
+    let mut failsafe = Failsafe::new(
+        &receiver.failsafe,
+        receiver.default_setpoint,
+        bus.logged("failsafe"),
+    );
+    let rc: Box<dyn RcSource> = if mock_hardware {
+        Box::new(MockRc::new(Inputs {
+            setpoint: receiver.default_setpoint,
+            mode: FlightMode::AltitudeHold,
+            ..Inputs::default()
+        }))
+    } else {
+        receiver.run();
+        bus.attach("rc_link", receiver.link.clone());
+        Box::new(receiver)
+    };
+    bus.attach("setpoint", rc.inputs());
 
-    let rate = Arc::new(Mutex::new(RateRingBuffer::new()));
+    let rate = bus.logged::<RateRingBuffer>("pid_rate");
 
-    let measurement: Arc<Mutex<State>> = Arc::new(Mutex::new(State::default()));
+    // raw sensor readings, the controller sees the altitude estimate instead of the sonar
+    let measurement = bus.logged::<State>("measurement");
 
-    let action: Arc<Mutex<ControlAction>> = Arc::new(Mutex::new(ControlAction {
-        port: 0.0,
-        starboard: 0.0,
-        aft: 0.0,
-        rudder: 0.0,
-    }));
+    let action = bus.logged::<ControlAction>("action");
 
-    let measurement_clone = measurement.clone();
-    thread::spawn(move || {
-        handle_imu(measurement_clone);
-    });
+    let calibration_request = bus.queue::<()>("imu/calibrate");
+    let imu_health = bus.logged::<ImuHealth>("imu_health");
+    let ages = bus.logged::<SignalAges>("age");
+    let sonar_status = bus.logged::<SonarStatus>("sonar");
+    if mock_hardware {
+        *imu_health.lock().unwrap() = ImuHealth {
+            healthy: true,
+            levelled: true,
+            ..ImuHealth::default()
+        };
+        sonar_status.lock().unwrap().valid = true;
+        spawn_attitude_source(
+            || MockAttitude::new(Duration::from_millis(16)),
+            measurement.clone(),
+        );
+        spawn_altitude_source(
+            || MockAltitude::new(Duration::from_millis(100)),
+            measurement.clone(),
+        );
+    } else {
+        let (imu, imu_bus) = (config.imu, bus.clone());
+        spawn_attitude_source(move || Imu::new(imu, &imu_bus), measurement.clone());
+        let (sonar_filter, sonar_mount, sonar_bus) =
+            (config.sonar_filter, config.sonar_mount, bus.clone());
+        spawn_altitude_source(
+            move || Sonar::new(sonar_filter, sonar_mount, &sonar_bus),
+            measurement.clone(),
+        );
+    }
 
-    let measurement_clone2 = measurement.clone();
-    thread::spawn(move || {
-        handle_sonar(measurement_clone2);
-    });
+    let mut altitude_estimator = AltitudeEstimator::new(config.altitude_estimator);
+    bus.attach("estimate", altitude_estimator.estimate.clone());
+    for (name, shared) in controller.telemetry() {
+        bus.attach(name, shared);
+    }
 
-    influx_log(
-        receiver.inputs.clone(),
-        "setpoint".to_string(),
-        Duration::from_millis(config.logging_interval_ms),
-    );
-    influx_log(
-        measurement.clone(),
-        "measurement".to_string(),
-        Duration::from_millis(config.logging_interval_ms),
-    );
-    influx_log(
-        action.clone(),
-        "action".to_string(),
-        Duration::from_millis(config.logging_interval_ms),
-    );
-    influx_log(
-        controller.current_pid.clone(),
-        "pid".to_string(),
-        Duration::from_millis(config.logging_interval_ms),
-    );
-    influx_log(
-        rate.clone(),
-        "pid_rate".to_string(),
-        Duration::from_millis(config.logging_interval_ms),
-    );
+    let mut actuators = if mock_hardware {
+        mock_actuators(&config.actuator_limits)
+    } else {
+        servo_actuators(&config.trim, &config.actuator_limits)
+    };
 
-    let mut port_servo = Servo::new(rppal::pwm::Channel::Pwm2, config.trim.port, -13.0, 13.0);
-    let mut starboard_servo = Servo::new(
-        rppal::pwm::Channel::Pwm0,
-        config.trim.starboard,
-        -13.0,
-        13.0,
+    let mut engagement = Engagement::new(
+        config.disengage_ramp_s,
+        config.manual_mix,
+        config.actuator_limits,
     );
-    let mut aft_servo = Servo::new(rppal::pwm::Channel::Pwm1, config.trim.aft, -13.0, 13.0);
-    let mut rudder_servo = Servo::new(rppal::pwm::Channel::Pwm3, config.trim.rudder, -135.0, 135.0);
 
-    let control_rate = Duration::from_millis(10);
+    let mut flight_modes = FlightModes::new(bus.logged("flight_mode"));
+
+    if config.battery.enabled && !mock_hardware {
+        spawn_battery(&config.battery, &bus);
+    }
+
+    // after the topics it reads are on the bus
+    if config.telemetry.enabled && !mock_hardware {
+        spawn_telemetry(&config.telemetry, &bus);
+    }
+
+    let mut calibrate_switch = false;
+
     loop {
         let start = SystemTime::now();
-        {
-            let inputs = receiver.get_inputs();
-            if inputs.controller_enable {
-                *action.lock().unwrap() = controller.update_controller(
-                    inputs.setpoint,
-                    *measurement.lock().unwrap(),
-                    control_rate.as_secs_f32(),
-                );
-            } else {
-                *action.lock().unwrap() = ControlAction::default();
-                controller.reset();
-            }
-        }
-        {
-            let unlocked_action = action.lock().unwrap();
-            port_servo.set_angle(unlocked_action.port);
-            starboard_servo.set_angle(unlocked_action.starboard);
-            aft_servo.set_angle(unlocked_action.aft);
-            rudder_servo.set_angle(unlocked_action.rudder * 3.0); // the servo has a gear ratio of 3
+        let mut inputs = rc.get_inputs();
+        failsafe.update(&mut inputs, Instant::now(), CONTROL_RATE.as_secs_f32());
+        // without a working, levelled IMU or with stale signals the flight mode falls back
+        let signal_ages = measurement.lock().unwrap().sampled.ages(Instant::now());
+        *ages.lock().unwrap() = signal_ages;
+        let guards = Guards {
+            attitude: imu_health.lock().unwrap().ready()
+                && config.max_age_ms.attitude_fresh(&signal_ages),
+            altitude: config.max_age_ms.altitude_fresh(&signal_ages),
+        };
+        flight_modes.update(&mut inputs, guards);
+        if inputs.calibrate && !calibrate_switch && !inputs.mode.controller() {
+            calibration_request.publish(());
         }
-        match control_rate.checked_sub(SystemTime::now().duration_since(start).unwrap()) {
+        calibrate_switch = inputs.calibrate;
+
+        let sonar = *sonar_status.lock().unwrap();
+        altitude_estimator.update(
+            *measurement.lock().unwrap(),
+            sonar,
+            CONTROL_RATE.as_secs_f32(),
+        );
+        control_step(
+            controller.as_mut(),
+            &mut engagement,
+            &inputs,
+            &altitude_estimator.estimate,
+            &action,
+            &mut actuators,
+            CONTROL_RATE.as_secs_f32(),
+        );
+        match CONTROL_RATE.checked_sub(SystemTime::now().duration_since(start).unwrap()) {
             Some(sleep_time) => sleep(sleep_time),
             None => println!("Wir sind am Arsch!"),
         }
@@ -134,3 +273,173 @@ fn main() -> () {
         }
     }
 }
+
+/// One iteration of the control loop: runs the controller if enabled and drives the actuators
+fn control_step(
+    controller: &mut dyn Controller,
+    engagement: &mut Engagement,
+    inputs: &Inputs,
+    measurement: &Mutex<State>,
+    action: &Mutex<ControlAction>,
+    actuators: &mut Actuators,
+    dt: f32,
+) {
+    {
+        if inputs.mode.controller() {
+            controller.set_autotune(inputs.mode == FlightMode::Autotune);
+            controller.set_altitude_hold(inputs.mode.altitude_hold());
+        }
+        let mut action = action.lock().unwrap();
+        *action = engagement.update(
+            controller,
+            inputs,
+            *measurement.lock().unwrap(),
+            *action,
+            dt,
+        );
+    }
+    actuators.apply(&action.lock().unwrap());
+}
+
+fn servo_actuators(trim: &ControlAction, limits: &ActuatorLimits) -> Actuators {
+    Actuators {
+        port: Box::new(Servo::new(
+            rppal::pwm::Channel::Pwm2,
+            trim.port,
+            limits.port.min,
+            limits.port.max,
+        )),
+        starboard: Box::new(Servo::new(
+            rppal::pwm::Channel::Pwm0,
+            trim.starboard,
+            limits.starboard.min,
+            limits.starboard.max,
+        )),
+        aft: Box::new(Servo::new(
+            rppal::pwm::Channel::Pwm1,
+            trim.aft,
+            limits.aft.min,
+            limits.aft.max,
+        )),
+        rudder: Box::new(Servo::new(
+            rppal::pwm::Channel::Pwm3,
+            trim.rudder,
+            limits.rudder.min * RUDDER_GEAR_RATIO,
+            limits.rudder.max * RUDDER_GEAR_RATIO,
+        )),
+    }
+}
+
+fn mock_actuators(limits: &ActuatorLimits) -> Actuators {
+    Actuators {
+        port: Box::new(MockActuator::new(limits.port.min, limits.port.max)),
+        starboard: Box::new(MockActuator::new(
+            limits.starboard.min,
+            limits.starboard.max,
+        )),
+        aft: Box::new(MockActuator::new(limits.aft.min, limits.aft.max)),
+        rudder: Box::new(MockActuator::new(
+            limits.rudder.min * RUDDER_GEAR_RATIO,
+            limits.rudder.max * RUDDER_GEAR_RATIO,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use control::FlightController;
+    use hal::AltitudeSource;
+    use std::sync::Arc;
+
+    const CONTROLLER: &str = "
+roll: { p: 0.04, i: 0.0, d: 0.0, i_limit: 25.0 }
+pitch: { p: 0.1, i: 0.0, d: 0.0, i_limit: 25.0 }
+yaw: { p: 0.3, i: 0.0, d: 0.0, i_limit: 25.0 }
+altitude: { p: 4.0, i: 1.0, d: 0.0, i_limit: 5.0 }
+mix_matrix:
+  - [ 15.0, 0.0, 0.0, -20.0]
+  - [-15.0, 0.0, 0.0, -15.0]
+  - [ 0.0, 15.0, 0.0, -15.0]
+  - [ 0.0,  0.0, 1.0,  0.0]
+";
+
+    #[test]
+    fn test_control_step_with_mocks() {
+        let mut controller: FlightController = serde_yaml::from_str(CONTROLLER).unwrap();
+        let mut inputs = Inputs {
+            setpoint: State {
+                roll: 10.0,
+                ..State::default()
+            },
+            mode: FlightMode::AltitudeHold,
+            ..Inputs::default()
+        };
+        let measurement = Mutex::new(State::default());
+        let action = Mutex::new(ControlAction::default());
+        let mut engagement = Engagement::default();
+
+        let limits = ActuatorLimits::default();
+        let port = MockActuator::new(limits.port.min, limits.port.max);
+        let port_angle = port.angle.clone();
+        let mut actuators = Actuators {
+            port: Box::new(port),
+            ..mock_actuators(&limits)
+        };
+
+        control_step(
+            &mut controller,
+            &mut engagement,
+            &inputs,
+            &measurement,
+            &action,
+            &mut actuators,
+            0.01,
+        );
+        assert!((6.0 - *port_angle.lock().unwrap()).abs() < 1e-4);
+
+        inputs.mode = FlightMode::Off;
+        control_step(
+            &mut controller,
+            &mut engagement,
+            &inputs,
+            &measurement,
+            &action,
+            &mut actuators,
+            0.01,
+        );
+        assert_eq!(0.0, *port_angle.lock().unwrap());
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config: Configuration = serde_yaml::from_str(include_str!("../config.yaml")).unwrap();
+        let mut controller = config.controller.build(config.actuator_limits);
+        assert!(sim::run(
+            controller.as_mut(),
+            config.receiver.default_setpoint,
+            &config.actuator_limits,
+            config.altitude_estimator,
+            &config.sim,
+            CONTROL_RATE.as_secs_f32(),
+            1000.0,
+        ));
+    }
+
+    #[test]
+    fn test_mock_sources() {
+        let measurement = Arc::new(Mutex::new(State::default()));
+
+        let mut attitude = MockAttitude::new(Duration::ZERO);
+        attitude.attitude.lock().unwrap().roll = 5.0;
+        attitude.poll(&measurement);
+
+        let mut altitude = MockAltitude::new(Duration::ZERO);
+        *altitude.altitude.lock().unwrap() = 0.4;
+        altitude.poll(&measurement);
+
+        let unlocked = measurement.lock().unwrap();
+        assert_eq!(5.0, unlocked.roll);
+        assert_eq!(0.4, unlocked.altitude);
+    }
+}
@@ -0,0 +1,206 @@
+use serde::Deserialize;
+
+use crate::control::ControlAction;
+
+/// Range of an actuator in degrees relative to trim
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Limit {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Limit {
+    fn symmetric(limit: f32) -> Self {
+        Self {
+            min: -limit,
+            max: limit,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ActuatorLimits {
+    pub port: Limit,
+    pub starboard: Limit,
+    pub aft: Limit,
+    /// at the rudder, not at the servo
+    pub rudder: Limit,
+}
+
+impl Default for ActuatorLimits {
+    fn default() -> Self {
+        Self {
+            port: Limit::symmetric(13.0),
+            starboard: Limit::symmetric(13.0),
+            aft: Limit::symmetric(13.0),
+            rudder: Limit::symmetric(45.0),
+        }
+    }
+}
+
+impl ActuatorLimits {
+    fn to_array(self) -> [Limit; 4] {
+        [self.port, self.starboard, self.aft, self.rudder]
+    }
+
+    pub fn clamp(&self, action: ControlAction) -> ControlAction {
+        let action: [f32; 4] = action.into();
+        let limits = self.to_array();
+        let mut clamped = [0.0; 4];
+        for i in 0..4 {
+            clamped[i] = action[i].clamp(limits[i].min, limits[i].max);
+        }
+        clamped.into()
+    }
+}
+
+/// Control axes in the order of the mix matrix columns
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Axis {
+    Roll,
+    Pitch,
+    Yaw,
+    Altitude,
+}
+
+impl Axis {
+    pub fn name(self) -> &'static str {
+        match self {
+            Axis::Roll => "roll",
+            Axis::Pitch => "pitch",
+            Axis::Yaw => "yaw",
+            Axis::Altitude => "altitude",
+        }
+    }
+}
+
+pub fn default_priority() -> [Axis; 4] {
+    [Axis::Roll, Axis::Pitch, Axis::Yaw, Axis::Altitude]
+}
+
+/// Order in which the mixer serves the axes, each axis exactly once
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "[Axis; 4]")]
+pub struct AxisPriority(pub [Axis; 4]);
+
+impl Default for AxisPriority {
+    fn default() -> Self {
+        Self(default_priority())
+    }
+}
+
+impl TryFrom<[Axis; 4]> for AxisPriority {
+    type Error = String;
+
+    fn try_from(axes: [Axis; 4]) -> Result<Self, Self::Error> {
+        // four entries containing all four axes cannot repeat one
+        if let Some(missing) = default_priority().iter().find(|axis| !axes.contains(axis)) {
+            return Err(format!(
+                "mixer: axis_priority must list every axis once, {} is missing",
+                missing.name()
+            ));
+        }
+        Ok(Self(axes))
+    }
+}
+
+/// Mixes the axis commands into actuator angles without exceeding the limits.
+/// The axes are added in order of priority, each one is scaled down as far as needed
+/// to fit into what the previous axes left.
+/// Returns the actuator angles and the achieved command of each axis
+pub fn mix(
+    matrix: &[[f32; 4]; 4],
+    limits: &ActuatorLimits,
+    priority: &[Axis; 4],
+    command: [f32; 4],
+) -> (ControlAction, [f32; 4]) {
+    let limits = limits.to_array();
+    let mut action = [0.0; 4];
+    let mut achieved = [0.0; 4];
+
+    for axis in priority {
+        let j = *axis as usize;
+        let mut scale: f32 = 1.0;
+        for i in 0..4 {
+            let contribution = matrix[i][j] * command[j];
+            let headroom = if contribution > 0.0 {
+                limits[i].max - action[i]
+            } else {
+                limits[i].min - action[i]
+            };
+            if contribution != 0.0 {
+                scale = scale.min(headroom / contribution);
+            }
+        }
+        let scale = scale.max(0.0);
+
+        achieved[j] = command[j] * scale;
+        for i in 0..4 {
+            action[i] += matrix[i][j] * achieved[j];
+        }
+    }
+
+    (action.into(), achieved)
+}
+
+/// Actuator angles for manual mode, the sticks (roll, pitch, yaw, altitude) are multiplied
+/// with the manual mix matrix and clamped to the limits
+pub fn manual_mix(
+    matrix: &[[f32; 4]; 4],
+    limits: &ActuatorLimits,
+    sticks: [f32; 4],
+) -> ControlAction {
+    let mut action = [0.0; 4];
+    for i in 0..4 {
+        for j in 0..4 {
+            action[i] += matrix[i][j] * sticks[j];
+        }
+    }
+    limits.clamp(action.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default_priority, mix, ActuatorLimits, Axis, AxisPriority};
+
+    const MATRIX: [[f32; 4]; 4] = [
+        [15.0, 0.0, 0.0, -20.0],
+        [-15.0, 0.0, 0.0, -15.0],
+        [0.0, 15.0, 0.0, -15.0],
+        [0.0, 0.0, 1.0, 0.0],
+    ];
+
+    #[test]
+    fn test_unsaturated() {
+        let (action, achieved) = mix(
+            &MATRIX,
+            &ActuatorLimits::default(),
+            &default_priority(),
+            [0.1, 0.1, 0.1, 0.1],
+        );
+        assert_eq!([0.1, 0.1, 0.1, 0.1], achieved);
+        assert!((action.port - -0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_priority() {
+        let limits = ActuatorLimits::default();
+        let (action, achieved) = mix(
+            &MATRIX,
+            &limits,
+            &[Axis::Roll, Axis::Altitude, Axis::Pitch, Axis::Yaw],
+            [0.5, 0.0, 0.0, 1.0],
+        );
+        // roll gets everything, altitude what is left on the starboard flap
+        assert_eq!(0.5, achieved[0]);
+        assert!((achieved[3] - 5.5 / 15.0).abs() < 1e-5);
+        assert!((action.starboard - limits.starboard.min).abs() < 1e-4);
+        assert!(action.port <= limits.port.max && action.port >= limits.port.min);
+
+        // every axis has to be mixed
+        let priority: AxisPriority = serde_yaml::from_str("[yaw, altitude, roll, pitch]").unwrap();
+        assert_eq!(Axis::Yaw, priority.0[0]);
+        assert!(serde_yaml::from_str::<AxisPriority>("[roll, roll, pitch, yaw]").is_err());
+    }
+}
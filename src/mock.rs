@@ -0,0 +1,113 @@
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::control::State;
+use crate::hal::{Actuator, AltitudeSource, AttitudeSource, RcSource};
+use crate::receiver::Inputs;
+
+/// In-memory actuator, remembers the last commanded angle
+pub struct MockActuator {
+    min_angle: f32,
+    max_angle: f32,
+    pub angle: Arc<Mutex<f32>>,
+}
+
+impl MockActuator {
+    /// the limits are applied like on a real servo
+    pub fn new(min_angle: f32, max_angle: f32) -> Self {
+        Self {
+            min_angle,
+            max_angle,
+            angle: Arc::new(Mutex::new(0.0)),
+        }
+    }
+}
+
+impl Actuator for MockActuator {
+    fn set_angle(&mut self, angle_grad: f32) {
+        *self.angle.lock().unwrap() = angle_grad.clamp(self.min_angle, self.max_angle);
+    }
+}
+
+/// Copies roll, pitch, the rates and the vertical acceleration from a shared state every interval
+pub struct MockAttitude {
+    pub attitude: Arc<Mutex<State>>,
+    interval: Duration,
+}
+
+impl MockAttitude {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            attitude: Arc::new(Mutex::new(State::default())),
+            interval,
+        }
+    }
+}
+
+impl AttitudeSource for MockAttitude {
+    fn poll(&mut self, measurement: &Mutex<State>) {
+        sleep(self.interval);
+        let attitude = *self.attitude.lock().unwrap();
+        let mut unlocked = measurement.lock().unwrap();
+        unlocked.roll = attitude.roll;
+        unlocked.pitch = attitude.pitch;
+        unlocked.yaw_rate = attitude.yaw_rate;
+        unlocked.pitch_rate = attitude.pitch_rate;
+        unlocked.vertical_acceleration = attitude.vertical_acceleration;
+        let now = Some(Instant::now());
+        unlocked.sampled.attitude = now;
+        unlocked.sampled.rates = now;
+        unlocked.sampled.acceleration = now;
+    }
+}
+
+/// Copies the altitude from a shared value every interval
+pub struct MockAltitude {
+    pub altitude: Arc<Mutex<f32>>,
+    interval: Duration,
+}
+
+impl MockAltitude {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            altitude: Arc::new(Mutex::new(0.0)),
+            interval,
+        }
+    }
+}
+
+impl AltitudeSource for MockAltitude {
+    fn poll(&mut self, measurement: &Mutex<State>) {
+        sleep(self.interval);
+        let mut unlocked = measurement.lock().unwrap();
+        unlocked.altitude = *self.altitude.lock().unwrap();
+        unlocked.sampled.altitude = Some(Instant::now());
+    }
+}
+
+/// Pilot inputs that are set directly instead of read from a receiver, the link never drops
+pub struct MockRc {
+    pub inputs: Arc<Mutex<Inputs>>,
+}
+
+impl MockRc {
+    pub fn new(inputs: Inputs) -> Self {
+        Self {
+            inputs: Arc::new(Mutex::new(inputs)),
+        }
+    }
+}
+
+impl RcSource for MockRc {
+    fn inputs(&self) -> Arc<Mutex<Inputs>> {
+        self.inputs.clone()
+    }
+
+    fn get_inputs(&self) -> Inputs {
+        Inputs {
+            received: Some(Instant::now()),
+            ..*self.inputs.lock().unwrap()
+        }
+    }
+}
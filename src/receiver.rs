@@ -1,26 +1,42 @@
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::channels::Channels;
 use crate::control::State;
+use crate::crsf::{CrsfPacket, CrsfParser};
+use crate::failsafe::FailsafeConfig;
+use crate::flight_mode::FlightMode;
+use crate::hal::RcSource;
 use crate::influx::{Log, Measurement};
+use crate::sbus::SbusParser;
 use parse_rc_ibus::{IbusPacket, ParsingError};
 use serde::Deserialize;
-use serialport;
+use serialport::{self, Parity, SerialPort, StopBits};
 
 // const IBUS_HEADER: [u8; 2] = [0x20, 0x40];
 
 #[derive(Clone, Copy)]
 pub struct Inputs {
     pub setpoint: State,
-    pub controller_enable: bool,
+    /// requested by the pilot, replaced by the active mode once the guards are checked
+    pub mode: FlightMode,
+    /// roll, pitch, yaw and altitude sticks from -1 to 1
+    pub sticks: [f32; 4],
+    /// starts a level calibration of the IMU while the controller is disabled
+    pub calibrate: bool,
+    /// when the last valid frame arrived, None before the first one
+    pub received: Option<Instant>,
 }
 
 impl Default for Inputs {
     fn default() -> Self {
         Self {
             setpoint: State::default(),
-            controller_enable: false,
+            mode: FlightMode::Off,
+            sticks: [0.0; 4],
+            calibrate: false,
+            received: None,
         }
     }
 }
@@ -31,67 +47,205 @@ impl Log for Inputs {
     }
 }
 
+/// Wire protocol of the receiver
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// FlySky
+    #[default]
+    Ibus,
+    /// Futaba, FrSky and most others
+    Sbus,
+    /// Crossfire and ExpressLRS
+    Crsf,
+}
+
+/// Frame counters of the receiver and the link statistics it reports
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkStatus {
+    pub good_frames: u32,
+    pub bad_frames: u32,
+    /// SBUS frames the receiver missed
+    pub frames_lost: u32,
+    /// the SBUS receiver sends its failsafe values
+    pub failsafe: bool,
+    /// CRSF uplink in dBm, percent and dB
+    pub rssi: f32,
+    pub link_quality: f32,
+    pub snr: f32,
+}
+
+impl Log for LinkStatus {
+    fn measurements(&self) -> Vec<Measurement> {
+        vec![
+            Measurement {
+                name: "good_frames",
+                value: self.good_frames as f32,
+            },
+            Measurement {
+                name: "bad_frames",
+                value: self.bad_frames as f32,
+            },
+            Measurement {
+                name: "frames_lost",
+                value: self.frames_lost as f32,
+            },
+            Measurement {
+                name: "failsafe",
+                value: self.failsafe as u8 as f32,
+            },
+            Measurement {
+                name: "rssi",
+                value: self.rssi,
+            },
+            Measurement {
+                name: "link_quality",
+                value: self.link_quality,
+            },
+            Measurement {
+                name: "snr",
+                value: self.snr,
+            },
+        ]
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Receiver {
+    #[serde(default)]
+    protocol: Protocol,
     sensitivity: State,
-    default_setpoint: State,
+    pub default_setpoint: State,
+    #[serde(default)]
+    channels: Channels,
+    #[serde(default)]
+    pub failsafe: FailsafeConfig,
 
     #[serde(skip_deserializing)]
     pub inputs: Arc<Mutex<Inputs>>,
+    #[serde(skip_deserializing)]
+    pub link: Arc<Mutex<LinkStatus>>,
 }
 
 impl Receiver {
     pub fn run(&self) {
-        let mut port = serialport::new("/dev/ttyAMA1", 115_200)
+        let (baud_rate, parity, stop_bits) = match self.protocol {
+            Protocol::Ibus => (115_200, Parity::None, StopBits::One),
+            Protocol::Sbus => (100_000, Parity::Even, StopBits::Two),
+            Protocol::Crsf => (420_000, Parity::None, StopBits::One),
+        };
+        let port = serialport::new("/dev/ttyAMA1", baud_rate)
+            .parity(parity)
+            .stop_bits(stop_bits)
             .timeout(Duration::from_millis(14))
             .open()
             .expect("Failed to open port");
 
-        let mut buffer = [0u8; 32];
-        let mut header_buffer = [0u8; 1];
-
         let inputs = Arc::clone(&self.inputs);
+        let link = Arc::clone(&self.link);
+        let protocol = self.protocol;
         let sensitivity = self.sensitivity;
+        let channels = self.channels.clone();
+        let failsafe = self.failsafe.clone();
 
         let default_setpoint = self.default_setpoint.clone();
 
-        thread::spawn(move || {
-            loop {
-                match port.read_exact(&mut buffer) {
-                    Ok(()) => {
-                        match IbusPacket::try_from_bytes(&buffer) {
-                            Ok(packet) => {
-                                // get channels and map from -1 to 1
-                                let channels: [f32; 14] = packet
-                                    .get_all_channels()
-                                    .map(|c| (c as f32 - 1500.0) / 500.0);
-
-                                let relative_setpoint = State {
-                                    roll: channels[0] * sensitivity.roll,
-                                    pitch: channels[1] * sensitivity.pitch,
-                                    yaw_rate: channels[3] * sensitivity.yaw_rate,
-                                    altitude: channels[6] * sensitivity.altitude,
-                                };
-                                let mut unlocked = inputs.lock().unwrap();
-                                unlocked.controller_enable = channels[5] > 0.6;
-                                unlocked.setpoint = default_setpoint + relative_setpoint;
-                            }
-                            Err(e) => match e {
-                                ParsingError::FailsChecksum => println!("invalid package"),
-                                ParsingError::InvalidPacket => {
-                                    println!("ibus desync!");
-                                    let _ = port.read(&mut header_buffer);
-                                }
-                            },
+        // maps the pulse widths of one frame to the inputs
+        let publish = move |raw: &[u16]| {
+            // the receiver lost the transmitter, keep the last inputs
+            if failsafe.transmitter_failsafe(raw) {
+                return;
+            }
+            *inputs.lock().unwrap() = Inputs {
+                received: Some(Instant::now()),
+                ..channels.inputs(raw, sensitivity, default_setpoint)
+            };
+        };
+
+        thread::spawn(move || match protocol {
+            Protocol::Ibus => read_ibus(port, &link, publish),
+            Protocol::Sbus => read_sbus(port, &link, publish),
+            Protocol::Crsf => read_crsf(port, &link, publish),
+        });
+    }
+}
+
+fn read_ibus(mut port: Box<dyn SerialPort>, link: &Mutex<LinkStatus>, publish: impl Fn(&[u16])) {
+    let mut buffer = [0u8; 32];
+    let mut header_buffer = [0u8; 1];
+    loop {
+        match port.read_exact(&mut buffer) {
+            Ok(()) => match IbusPacket::try_from_bytes(&buffer) {
+                Ok(packet) => {
+                    link.lock().unwrap().good_frames += 1;
+                    publish(&packet.get_all_channels());
+                }
+                Err(e) => {
+                    link.lock().unwrap().bad_frames += 1;
+                    match e {
+                        ParsingError::FailsChecksum => println!("invalid package"),
+                        ParsingError::InvalidPacket => {
+                            println!("ibus desync!");
+                            let _ = port.read(&mut header_buffer);
                         }
                     }
-                    Err(_) => {} //println!("Reveiver Error {:?}", e),
                 }
+            },
+            Err(_) => {} //println!("Reveiver Error {:?}", e),
+        }
+    }
+}
+
+fn read_sbus(mut port: Box<dyn SerialPort>, link: &Mutex<LinkStatus>, publish: impl Fn(&[u16])) {
+    let mut parser = SbusParser::default();
+    let mut buffer = [0u8; 25];
+    loop {
+        let n = port.read(&mut buffer).unwrap_or(0);
+        for byte in &buffer[..n] {
+            let Some(frame) = parser.push(*byte) else {
+                continue;
+            };
+            {
+                let mut link = link.lock().unwrap();
+                link.frames_lost += frame.frame_lost as u32;
+                link.failsafe = frame.failsafe;
             }
-        });
+            // a lost frame repeats the last values, only new ones hold off the link-loss failsafe
+            if !frame.frame_lost && !frame.failsafe {
+                publish(&frame.channels);
+            }
+        }
+        let mut link = link.lock().unwrap();
+        link.good_frames = parser.good_frames;
+        link.bad_frames = parser.bad_frames;
     }
+}
+
+fn read_crsf(mut port: Box<dyn SerialPort>, link: &Mutex<LinkStatus>, publish: impl Fn(&[u16])) {
+    let mut parser = CrsfParser::default();
+    let mut buffer = [0u8; 64];
+    loop {
+        let n = port.read(&mut buffer).unwrap_or(0);
+        for byte in &buffer[..n] {
+            match parser.push(*byte) {
+                Some(CrsfPacket::Channels(channels)) => publish(&channels),
+                Some(CrsfPacket::LinkStatistics(statistics)) => {
+                    let mut link = link.lock().unwrap();
+                    link.rssi = statistics.rssi as f32;
+                    link.link_quality = statistics.link_quality as f32;
+                    link.snr = statistics.snr as f32;
+                }
+                None => {}
+            }
+        }
+        let mut link = link.lock().unwrap();
+        link.good_frames = parser.good_frames;
+        link.bad_frames = parser.bad_frames;
+    }
+}
 
-    pub fn get_inputs(&self) -> Inputs {
-        *self.inputs.lock().unwrap()
+impl RcSource for Receiver {
+    fn inputs(&self) -> Arc<Mutex<Inputs>> {
+        self.inputs.clone()
     }
 }
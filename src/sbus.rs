@@ -0,0 +1,135 @@
+/// First byte of every frame
+const HEADER: u8 = 0x0F;
+const FRAME_LEN: usize = 25;
+
+/// Channel values of SBUS and CRSF, 172 to 1811 around 992
+const CENTER: i32 = 992;
+
+/// Unpacks 16 channels of 11 bits, least significant bit first, and converts them to pulse widths in µs
+pub fn unpack_channels(data: &[u8]) -> [u16; 16] {
+    let mut channels = [0u16; 16];
+    for (i, channel) in channels.iter_mut().enumerate() {
+        let bit = i * 11;
+        let bits = data[bit / 8] as u32
+            | (data[bit / 8 + 1] as u32) << 8
+            | (*data.get(bit / 8 + 2).unwrap_or(&0) as u32) << 16;
+        let value = ((bits >> (bit % 8)) & 0x7FF) as i32;
+        *channel = (1500 + (value - CENTER) * 5 / 8) as u16;
+    }
+    channels
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SbusFrame {
+    /// pulse widths in µs
+    pub channels: [u16; 16],
+    /// the receiver missed this frame and repeats the last one
+    pub frame_lost: bool,
+    /// the receiver lost the transmitter and sends its failsafe values
+    pub failsafe: bool,
+}
+
+/// Parser for the 25 byte SBUS frames: 0x0F, 22 bytes of channels, flags, footer.
+/// SBUS is inverted, the UART needs an inverter in front of it.
+/// After a bad frame the parser resynchronises on the next 0x0F, byte by byte
+#[derive(Debug)]
+pub struct SbusParser {
+    frame: [u8; FRAME_LEN],
+    len: usize,
+    pub good_frames: u32,
+    pub bad_frames: u32,
+}
+
+impl Default for SbusParser {
+    fn default() -> Self {
+        Self {
+            frame: [0; FRAME_LEN],
+            len: 0,
+            good_frames: 0,
+            bad_frames: 0,
+        }
+    }
+}
+
+impl SbusParser {
+    /// Feeds one byte. Returns the frame when it completes a valid one
+    pub fn push(&mut self, byte: u8) -> Option<SbusFrame> {
+        if self.len == 0 && byte != HEADER {
+            return None;
+        }
+        self.frame[self.len] = byte;
+        self.len += 1;
+        if self.len < FRAME_LEN {
+            return None;
+        }
+
+        // SBUS2 receivers put a telemetry slot into the footer
+        let footer = self.frame[FRAME_LEN - 1];
+        if footer == 0x00 || footer & 0x0F == 0x04 {
+            self.good_frames += 1;
+            self.len = 0;
+            let flags = self.frame[23];
+            return Some(SbusFrame {
+                channels: unpack_channels(&self.frame[1..23]),
+                frame_lost: flags & 0x04 != 0,
+                failsafe: flags & 0x08 != 0,
+            });
+        }
+
+        // the next frame may already have started within this one
+        self.bad_frames += 1;
+        self.len = 0;
+        for i in 1..FRAME_LEN {
+            if self.frame[i] == HEADER {
+                let rest = FRAME_LEN - i;
+                self.frame.copy_within(i.., 0);
+                self.len = rest;
+                break;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SbusParser;
+
+    /// Packs 11 bit channel values into a frame
+    fn frame(values: [u16; 16], flags: u8) -> Vec<u8> {
+        let mut data = [0u8; 22];
+        for (i, value) in values.iter().enumerate() {
+            for b in 0..11 {
+                if value & (1 << b) != 0 {
+                    let bit = i * 11 + b;
+                    data[bit / 8] |= 1 << (bit % 8);
+                }
+            }
+        }
+        let mut frame = vec![0x0F];
+        frame.extend(data);
+        frame.extend([flags, 0x00]);
+        frame
+    }
+
+    #[test]
+    fn test_sbus() {
+        let mut parser = SbusParser::default();
+        let mut values = [992u16; 16];
+        values[0] = 1811;
+        values[15] = 172;
+        // garbage and a cut off frame before
+        let mut bytes = vec![0x12, 0x0F, 0x00];
+        bytes.extend(frame(values, 0x00));
+        bytes.extend(frame(values, 0x0C));
+        let frames: Vec<_> = bytes.iter().filter_map(|b| parser.push(*b)).collect();
+
+        assert_eq!(2, frames.len());
+        assert_eq!(2011, frames[0].channels[0]);
+        assert_eq!(1500, frames[0].channels[1]);
+        assert_eq!(988, frames[0].channels[15]);
+        assert!(!frames[0].failsafe && !frames[0].frame_lost);
+        assert!(frames[1].failsafe && frames[1].frame_lost);
+        assert_eq!(1, parser.bad_frames);
+    }
+}
@@ -0,0 +1,241 @@
+use serde::Deserialize;
+
+use crate::influx::{Log, Measurement};
+
+/// PID gains of one axis
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+pub struct Gains {
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+}
+
+/// Gains at one value of the scheduling variable
+#[derive(Debug, Deserialize)]
+pub struct Breakpoint {
+    at: f32,
+    p: f32,
+    i: f32,
+    d: f32,
+}
+
+impl Breakpoint {
+    fn gains(&self) -> Gains {
+        Gains {
+            p: self.p,
+            i: self.i,
+            d: self.d,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GainTables {
+    #[serde(default)]
+    speed: Vec<Breakpoint>,
+    #[serde(default)]
+    altitude: Vec<Breakpoint>,
+}
+
+/// Gain tables of one axis.
+/// The speed table is used while a speed measurement is available, the altitude table otherwise.
+/// Nothing measures the speed yet, so a speed table is rejected instead of silently never used.
+/// Breakpoints must be in strictly ascending order, outside of them the gains are held constant
+#[derive(Debug, Default, Deserialize)]
+#[serde(try_from = "GainTables")]
+pub struct GainSchedule {
+    speed: Vec<Breakpoint>,
+    altitude: Vec<Breakpoint>,
+}
+
+fn validate(name: &str, table: &[Breakpoint]) -> Result<(), String> {
+    if table.iter().any(|b| !b.at.is_finite()) {
+        return Err(format!("schedule: {} breakpoints must be finite", name));
+    }
+    if table.windows(2).any(|w| w[0].at >= w[1].at) {
+        return Err(format!(
+            "schedule: {} breakpoints must be strictly ascending",
+            name
+        ));
+    }
+    Ok(())
+}
+
+impl TryFrom<GainTables> for GainSchedule {
+    type Error = String;
+
+    fn try_from(tables: GainTables) -> Result<Self, Self::Error> {
+        if !tables.speed.is_empty() {
+            return Err("schedule: there is no speed sensor, use an altitude table".to_string());
+        }
+        validate("altitude", &tables.altitude)?;
+        Ok(Self {
+            speed: tables.speed,
+            altitude: tables.altitude,
+        })
+    }
+}
+
+impl GainSchedule {
+    /// Returns the interpolated gains, or None if there is no table for the available variables
+    pub fn gains(&self, speed: Option<f32>, altitude: f32) -> Option<Gains> {
+        match speed {
+            Some(speed) if !self.speed.is_empty() => interpolate(&self.speed, speed),
+            _ => interpolate(&self.altitude, altitude),
+        }
+    }
+}
+
+fn interpolate(table: &[Breakpoint], x: f32) -> Option<Gains> {
+    let first = table.first()?;
+    let last = table.last()?;
+    if x <= first.at {
+        return Some(first.gains());
+    }
+    if x >= last.at {
+        return Some(last.gains());
+    }
+    table.windows(2).find_map(|w| {
+        let (low, high) = (&w[0], &w[1]);
+        if x < low.at || x > high.at {
+            return None;
+        }
+        let t = (x - low.at) / (high.at - low.at);
+        Some(Gains {
+            p: low.p + t * (high.p - low.p),
+            i: low.i + t * (high.i - low.i),
+            d: low.d + t * (high.d - low.d),
+        })
+    })
+}
+
+/// The active gains of all axes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ActiveGains {
+    pub roll: Gains,
+    pub pitch: Gains,
+    pub yaw: Gains,
+    pub altitude: Gains,
+}
+
+impl Log for ActiveGains {
+    fn measurements(&self) -> Vec<Measurement> {
+        vec![
+            Measurement {
+                name: "roll_p",
+                value: self.roll.p,
+            },
+            Measurement {
+                name: "roll_i",
+                value: self.roll.i,
+            },
+            Measurement {
+                name: "roll_d",
+                value: self.roll.d,
+            },
+            Measurement {
+                name: "pitch_p",
+                value: self.pitch.p,
+            },
+            Measurement {
+                name: "pitch_i",
+                value: self.pitch.i,
+            },
+            Measurement {
+                name: "pitch_d",
+                value: self.pitch.d,
+            },
+            Measurement {
+                name: "yaw_p",
+                value: self.yaw.p,
+            },
+            Measurement {
+                name: "yaw_i",
+                value: self.yaw.i,
+            },
+            Measurement {
+                name: "yaw_d",
+                value: self.yaw.d,
+            },
+            Measurement {
+                name: "altitude_p",
+                value: self.altitude.p,
+            },
+            Measurement {
+                name: "altitude_i",
+                value: self.altitude.i,
+            },
+            Measurement {
+                name: "altitude_d",
+                value: self.altitude.d,
+            },
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Breakpoint, GainSchedule, Gains};
+
+    #[test]
+    fn test_interpolation() {
+        // speed tables cannot be loaded yet
+        let schedule = GainSchedule {
+            speed: vec![
+                Breakpoint {
+                    at: 2.0,
+                    p: 1.0,
+                    i: 0.0,
+                    d: 0.0,
+                },
+                Breakpoint {
+                    at: 4.0,
+                    p: 3.0,
+                    i: 1.0,
+                    d: 0.0,
+                },
+            ],
+            altitude: vec![Breakpoint {
+                at: 0.0,
+                p: 5.0,
+                i: 0.0,
+                d: 0.0,
+            }],
+        };
+
+        assert_eq!(
+            Some(Gains {
+                p: 2.0,
+                i: 0.5,
+                d: 0.0
+            }),
+            schedule.gains(Some(3.0), 0.3)
+        );
+        // held constant outside of the breakpoints
+        assert_eq!(Some(3.0), schedule.gains(Some(10.0), 0.3).map(|g| g.p));
+        // altitude is the fallback without a speed measurement
+        assert_eq!(Some(5.0), schedule.gains(None, 0.3).map(|g| g.p));
+        assert_eq!(None, GainSchedule::default().gains(Some(3.0), 0.3));
+    }
+
+    #[test]
+    fn test_breakpoint_order() {
+        let duplicate = "
+altitude:
+  - { at: 0.2, p: 1.0, i: 0.0, d: 0.0 }
+  - { at: 0.2, p: 2.0, i: 0.0, d: 0.0 }
+";
+        assert!(serde_yaml::from_str::<GainSchedule>(duplicate).is_err());
+        let unsorted = "
+altitude:
+  - { at: 0.4, p: 1.0, i: 0.0, d: 0.0 }
+  - { at: 0.2, p: 2.0, i: 0.0, d: 0.0 }
+";
+        assert!(serde_yaml::from_str::<GainSchedule>(unsorted).is_err());
+        let speed = "
+speed:
+  - { at: 4.0, p: 1.0, i: 0.0, d: 0.0 }
+";
+        assert!(serde_yaml::from_str::<GainSchedule>(speed).is_err());
+    }
+}
@@ -5,6 +5,8 @@ use std::{
     time::Duration,
 };
 
+use crate::hal::Actuator;
+
 /// Represents a servo connected to one of the Pi's PWM channels.
 pub struct Servo {
     pwm: Pwm,
@@ -45,9 +47,11 @@ impl Servo {
 
         return s;
     }
+}
 
+impl Actuator for Servo {
     /// Sets the servo angle in grad
-    pub fn set_angle(&mut self, angle_grad: f32) {
+    fn set_angle(&mut self, angle_grad: f32) {
         // apply limits and trim
         let angle = angle_grad.clamp(self.min_angle, self.max_angle) + self.trim;
 
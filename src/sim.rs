@@ -0,0 +1,336 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::control::{ControlAction, Controller, State};
+use crate::control_step;
+use crate::engage::Engagement;
+use crate::estimator::{AltitudeEstimator, EstimatorConfig};
+use crate::flight_mode::FlightMode;
+use crate::hal::{Actuators, RcSource, RUDDER_GEAR_RATIO};
+use crate::mixer::ActuatorLimits;
+use crate::mock::{MockActuator, MockRc};
+use crate::receiver::Inputs;
+use crate::sonar_filter::SonarStatus;
+use crate::staleness::SampleTimes;
+
+/// Parameters of the simulated foiling boat.
+/// Flap angles are in degrees relative to trim, a negative flap angle produces more lift.
+/// All gains are accelerations per unit of input, angles are in degrees and altitude is in meter
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct BoatParameters {
+    /// lift change per degree of flap
+    flap_lift: f32,
+    heave_gain: f32,
+    heave_damping: f32,
+    /// the foils lose lift when approaching the surface
+    surface_stiffness: f32,
+    /// altitude where the foils are in equilibrium at trim
+    ride_height: f32,
+    /// additional lift per degree of pitch
+    pitch_heave: f32,
+
+    roll_gain: f32,
+    roll_damping: f32,
+    roll_stiffness: f32,
+
+    pitch_gain: f32,
+    pitch_damping: f32,
+    pitch_stiffness: f32,
+    /// pitch where the boat is in equilibrium at trim
+    trim_pitch: f32,
+
+    rudder_gain: f32,
+    yaw_damping: f32,
+}
+
+impl Default for BoatParameters {
+    fn default() -> Self {
+        Self {
+            flap_lift: 1.0,
+            heave_gain: 0.05,
+            heave_damping: 2.0,
+            surface_stiffness: 4.0,
+            ride_height: 0.2,
+            pitch_heave: 0.1,
+            roll_gain: 40.0,
+            roll_damping: 4.0,
+            roll_stiffness: 2.0,
+            pitch_gain: 20.0,
+            pitch_damping: 5.0,
+            pitch_stiffness: 8.0,
+            trim_pitch: 3.0,
+            rudder_gain: 2.0,
+            yaw_damping: 1.5,
+        }
+    }
+}
+
+/// A setpoint change at a given time
+#[derive(Debug, Deserialize)]
+pub struct SetpointStep {
+    time: f32,
+    setpoint: State,
+    /// position of the autotune switch from then on
+    #[serde(default)]
+    autotune: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SimConfig {
+    duration_s: f32,
+    boat: BoatParameters,
+    initial: State,
+    steps: Vec<SetpointStep>,
+    /// sinusoidal waves seen by the sonar, in meter and seconds
+    wave_amplitude: f32,
+    wave_period: f32,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            duration_s: 30.0,
+            boat: BoatParameters::default(),
+            initial: State::default(),
+            steps: Vec::new(),
+            wave_amplitude: 0.0,
+            wave_period: 3.0,
+        }
+    }
+}
+
+/// Rigid body state of the simulated boat
+#[derive(Debug, Default)]
+struct Boat {
+    altitude: f32,
+    climb_rate: f32,
+    heave_acceleration: f32,
+    roll: f32,
+    roll_rate: f32,
+    pitch: f32,
+    pitch_rate: f32,
+    yaw_rate: f32,
+}
+
+impl Boat {
+    fn new(initial: State) -> Self {
+        Self {
+            altitude: initial.altitude,
+            roll: initial.roll,
+            pitch: initial.pitch,
+            yaw_rate: initial.yaw_rate,
+            pitch_rate: initial.pitch_rate,
+            ..Self::default()
+        }
+    }
+
+    /// Integrates the boat for one time step with the achieved flap and rudder angles
+    fn step(&mut self, params: &BoatParameters, flaps: &ControlAction, dt: f32) {
+        let lift_port = -params.flap_lift * flaps.port;
+        let lift_starboard = -params.flap_lift * flaps.starboard;
+        let lift_aft = -params.flap_lift * flaps.aft;
+
+        let heave_acceleration = params.heave_gain * (lift_port + lift_starboard + lift_aft)
+            + params.pitch_heave * (self.pitch - params.trim_pitch)
+            - params.heave_damping * self.climb_rate
+            - params.surface_stiffness * (self.altitude - params.ride_height);
+        let roll_acceleration = params.roll_gain * (lift_starboard - lift_port)
+            - params.roll_damping * self.roll_rate
+            - params.roll_stiffness * self.roll;
+        let pitch_acceleration = params.pitch_gain
+            * ((lift_port + lift_starboard) / 2.0 - lift_aft)
+            - params.pitch_damping * self.pitch_rate
+            - params.pitch_stiffness * (self.pitch - params.trim_pitch);
+        let yaw_acceleration =
+            params.rudder_gain * flaps.rudder - params.yaw_damping * self.yaw_rate;
+
+        // semi-implicit euler
+        self.heave_acceleration = heave_acceleration;
+        self.climb_rate += heave_acceleration * dt;
+        self.altitude += self.climb_rate * dt;
+        self.roll_rate += roll_acceleration * dt;
+        self.roll += self.roll_rate * dt;
+        self.pitch_rate += pitch_acceleration * dt;
+        self.pitch += self.pitch_rate * dt;
+        self.yaw_rate += yaw_acceleration * dt;
+    }
+
+    /// All sensors sampled at `now`
+    fn measure(&self, wave: f32, now: Instant) -> State {
+        State {
+            roll: self.roll,
+            pitch: self.pitch,
+            yaw_rate: self.yaw_rate,
+            altitude: self.altitude - wave,
+            pitch_rate: self.pitch_rate,
+            climb_rate: self.climb_rate,
+            vertical_acceleration: self.heave_acceleration,
+            sampled: SampleTimes {
+                attitude: Some(now),
+                rates: Some(now),
+                altitude: Some(now),
+                acceleration: Some(now),
+            },
+        }
+    }
+
+    fn diverged(&self) -> bool {
+        !self.altitude.is_finite()
+            || self.altitude.abs() > 2.0
+            || self.roll.abs() > 45.0
+            || self.pitch.abs() > 45.0
+    }
+}
+
+/// Root mean square error per axis
+#[derive(Default)]
+struct RmsError {
+    sum: [f32; 4],
+    samples: u32,
+}
+
+impl RmsError {
+    fn push(&mut self, setpoint: State, measurement: State) {
+        let setpoint: [f32; 4] = setpoint.into();
+        let measurement: [f32; 4] = measurement.into();
+        for i in 0..4 {
+            self.sum[i] += (setpoint[i] - measurement[i]).powi(2);
+        }
+        self.samples += 1;
+    }
+
+    fn rms(&self) -> State {
+        self.sum
+            .map(|s| (s / self.samples.max(1) as f32).sqrt())
+            .into()
+    }
+}
+
+/// Runs the flight controller against the simulated boat, faster than real time.
+/// Prints the setpoint, measurement and action as CSV every logging interval.
+/// Returns false if the boat diverged
+pub fn run(
+    controller: &mut dyn Controller,
+    default_setpoint: State,
+    limits: &ActuatorLimits,
+    estimator: EstimatorConfig,
+    config: &SimConfig,
+    dt: f32,
+    logging_interval: f32,
+) -> bool {
+    let rc = MockRc::new(Inputs {
+        setpoint: default_setpoint,
+        mode: FlightMode::AltitudeHold,
+        ..Inputs::default()
+    });
+    let measurement = Mutex::new(config.initial);
+    let action = Mutex::new(ControlAction::default());
+    let mut engagement = Engagement::default();
+    let mut estimator = AltitudeEstimator::new(estimator);
+    // the simulated sonar never drops out
+    let sonar = SonarStatus {
+        valid: true,
+        ..SonarStatus::default()
+    };
+
+    // the mock actuators apply the same limits as the servos
+    let port = MockActuator::new(limits.port.min, limits.port.max);
+    let starboard = MockActuator::new(limits.starboard.min, limits.starboard.max);
+    let aft = MockActuator::new(limits.aft.min, limits.aft.max);
+    let rudder = MockActuator::new(
+        limits.rudder.min * RUDDER_GEAR_RATIO,
+        limits.rudder.max * RUDDER_GEAR_RATIO,
+    );
+    let achieved = [&port.angle, &starboard.angle, &aft.angle, &rudder.angle].map(Arc::clone);
+    let mut actuators = Actuators {
+        port: Box::new(port),
+        starboard: Box::new(starboard),
+        aft: Box::new(aft),
+        rudder: Box::new(rudder),
+    };
+
+    let mut boat = Boat::new(config.initial);
+    let mut error = RmsError::default();
+    let steps = (config.duration_s / dt) as u32;
+    let start = Instant::now();
+    let log_every = ((logging_interval / dt) as u32).max(1);
+
+    println!(
+        "time,setpoint_roll,setpoint_pitch,setpoint_yaw_rate,setpoint_altitude,\
+         roll,pitch,yaw_rate,altitude,port,starboard,aft,rudder"
+    );
+    for step in 0..steps {
+        let time = step as f32 * dt;
+        for s in config.steps.iter().filter(|s| s.time <= time) {
+            let mut inputs = rc.inputs.lock().unwrap();
+            inputs.setpoint = s.setpoint;
+            inputs.mode = if s.autotune {
+                FlightMode::Autotune
+            } else {
+                FlightMode::AltitudeHold
+            };
+        }
+
+        let wave =
+            config.wave_amplitude * (2.0 * std::f32::consts::PI * time / config.wave_period).sin();
+        // a new sample every step, the sim runs faster than the clock
+        let now = start + Duration::from_secs_f32(time);
+        *measurement.lock().unwrap() = estimator.update(boat.measure(wave, now), sonar, dt);
+
+        control_step(
+            controller,
+            &mut engagement,
+            &rc.get_inputs(),
+            &measurement,
+            &action,
+            &mut actuators,
+            dt,
+        );
+
+        let flaps = ControlAction::from(achieved.each_ref().map(|a| *a.lock().unwrap()));
+        let flaps = ControlAction {
+            rudder: flaps.rudder / RUDDER_GEAR_RATIO,
+            ..flaps
+        };
+        boat.step(&config.boat, &flaps, dt);
+
+        let setpoint = rc.get_inputs().setpoint;
+        let m = boat.measure(0.0, now);
+        error.push(setpoint, m);
+
+        if step % log_every == 0 {
+            println!(
+                "{:.2},{},{},{},{},{},{},{},{},{},{},{},{}",
+                time,
+                setpoint.roll,
+                setpoint.pitch,
+                setpoint.yaw_rate,
+                setpoint.altitude,
+                m.roll,
+                m.pitch,
+                m.yaw_rate,
+                m.altitude,
+                flaps.port,
+                flaps.starboard,
+                flaps.aft,
+                flaps.rudder
+            );
+        }
+
+        if boat.diverged() {
+            eprintln!("[Sim] diverged at {:.2}s: {:?}", time, boat);
+            return false;
+        }
+    }
+
+    let rms = error.rms();
+    eprintln!(
+        "[Sim] rms error roll: {:.3} pitch: {:.3} yaw_rate: {:.3} altitude: {:.3}",
+        rms.roll, rms.pitch, rms.yaw_rate, rms.altitude
+    );
+    true
+}
@@ -1,38 +1,124 @@
-use serialport;
+use nalgebra::{Rotation3, Vector3};
+use serde::Deserialize;
+use serialport::{self, SerialPort};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::bus::Bus;
 use crate::control::State;
+use crate::hal::AltitudeSource;
+use crate::sonar_filter::{SonarFilter, SonarFilterConfig, SonarStatus};
+use crate::sonar_frame::FrameParser;
 
-const START: u8 = 0xFF;
-
-pub fn handle_sonar(distance: Arc<Mutex<State>>) {
-    let mut port = serialport::new("/dev/ttyAMA2", 9600)
-        .timeout(Duration::from_millis(30))
-        .open()
-        .expect("Failed to open port");
-
-    let mut buffer = [0u8; 4];
-
-    loop {
-        let _ = port.read_exact(&mut buffer);
-
-        if buffer[0] == START {
-            let distance_mm: u16 = u16::from_be_bytes([buffer[1], buffer[2]]);
-
-            // dbg!(buffer);
-            //dbg!(distance_mm);
-            distance.lock().unwrap().altitude = distance_mm as f32 / 1000.0;
-            // checksum
-            // ToDo: fix
-            // if buffer[1] + buffer[2] != buffer[3] {
-            //     println!("sonar, checksum error");
-            // } else {
-            //     *distance.lock().unwrap() = distance_mm as f32 / 1000.0;
-            // }
-        } else {
-            let mut null = [0u8; 0];
-            let _ = port.read_exact(&mut null);
+/// Below this the beam is too flat to the water for a usable reading, cos(60°)
+const MIN_VERTICAL: f32 = 0.5;
+
+/// Where the sonar sits on the boat. Body frame: x forward, y starboard, z down
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct SonarMount {
+    /// tilt of the beam against straight down, in degrees
+    pub roll: f32,
+    pub pitch: f32,
+    /// position of the sonar relative to the point whose altitude is controlled, e.g. the foil, in meter
+    pub lever_arm: [f32; 3],
+}
+
+impl SonarMount {
+    /// Altitude of the reference point from the slant range along the beam and the attitude of the boat in degrees.
+    /// None if the beam is too flat to the water
+    pub fn altitude(&self, range: f32, roll: f32, pitch: f32) -> Option<f32> {
+        let attitude = Rotation3::from_euler_angles(roll.to_radians(), pitch.to_radians(), 0.0);
+        let mount =
+            Rotation3::from_euler_angles(self.roll.to_radians(), self.pitch.to_radians(), 0.0);
+        let beam = attitude * mount * Vector3::z();
+        if beam.z < MIN_VERTICAL {
+            return None;
+        }
+        let lever_arm = attitude * Vector3::from(self.lever_arm);
+        Some(range * beam.z + lever_arm.z)
+    }
+}
+
+/// Ultrasonic distance sensor on the Pi's UART.
+/// Only valid readings are written into the measurement, corrected for the roll and pitch of the boat
+pub struct Sonar {
+    port: Box<dyn SerialPort>,
+    parser: FrameParser,
+    filter: SonarFilter,
+    mount: SonarMount,
+    last_sample: Instant,
+    status: Arc<Mutex<SonarStatus>>,
+}
+
+impl Sonar {
+    pub fn new(config: SonarFilterConfig, mount: SonarMount, bus: &Bus) -> Self {
+        let port = serialport::new("/dev/ttyAMA2", 9600)
+            .timeout(Duration::from_millis(30))
+            .open()
+            .expect("Failed to open port");
+
+        Self {
+            port,
+            parser: FrameParser::default(),
+            filter: SonarFilter::new(config),
+            mount,
+            last_sample: Instant::now(),
+            status: bus.logged("sonar"),
+        }
+    }
+}
+
+impl AltitudeSource for Sonar {
+    fn poll(&mut self, distance: &Mutex<State>) {
+        let mut buffer = [0u8; 4];
+
+        let n = self.port.read(&mut buffer).unwrap_or(0);
+
+        for byte in &buffer[..n] {
+            let Some(distance_mm) = self.parser.push(*byte) else {
+                continue;
+            };
+            let dt = self.last_sample.elapsed().as_secs_f32();
+            self.last_sample = Instant::now();
+            if let Some(range) = self.filter.filter(distance_mm as f32 / 1000.0, dt) {
+                let mut unlocked = distance.lock().unwrap();
+                if let Some(altitude) = self.mount.altitude(range, unlocked.roll, unlocked.pitch) {
+                    unlocked.altitude = altitude;
+                    unlocked.sampled.altitude = Some(Instant::now());
+                }
+            }
         }
+
+        let mut status = self.status.lock().unwrap();
+        *status = self.filter.status;
+        status.good_frames = self.parser.good_frames;
+        status.bad_frames = self.parser.bad_frames;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SonarMount;
+
+    #[test]
+    fn test_tilt_compensation() {
+        let mount = SonarMount::default();
+        assert_eq!(Some(0.5), mount.altitude(0.5, 0.0, 0.0));
+        let altitude = mount.altitude(0.5, 10.0, 0.0).unwrap();
+        assert!((altitude - 0.5 * 10f32.to_radians().cos()).abs() < 1e-5);
+        assert_eq!(None, mount.altitude(0.5, 70.0, 0.0));
+
+        // sonar 1 m above and 0.5 m ahead of the foil, the bow is up
+        let mount = SonarMount {
+            lever_arm: [0.5, 0.0, -1.0],
+            ..SonarMount::default()
+        };
+        let altitude = mount.altitude(0.5, 0.0, 0.0).unwrap();
+        assert!((altitude - -0.5).abs() < 1e-5);
+        let pitch = 5f32.to_radians();
+        let altitude = mount.altitude(0.5, 0.0, 5.0).unwrap();
+        let expected = 0.5 * pitch.cos() - pitch.cos() - 0.5 * pitch.sin();
+        assert!((altitude - expected).abs() < 1e-5);
     }
 }
@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+
+use serde::Deserialize;
+
+use crate::influx::{Log, Measurement};
+
+/// Deviations below the resolution of the sonar are never outliers
+const RESOLUTION: f32 = 0.01;
+
+/// Validation of the sonar distance, in meter and seconds
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct SonarFilterConfig {
+    /// readings outside of the range are dropouts, e.g. zero without an echo
+    pub min_range: f32,
+    pub max_range: f32,
+    /// samples of the Hampel filter
+    pub window: usize,
+    /// a sample further from the median than this many standard deviations is an outlier
+    pub outlier_threshold: f32,
+    /// maximum plausible rate of change against the last valid sample
+    pub max_rate: f32,
+}
+
+impl Default for SonarFilterConfig {
+    fn default() -> Self {
+        Self {
+            min_range: 0.03,
+            max_range: 4.0,
+            window: 5,
+            outlier_threshold: 3.0,
+            max_rate: 2.0,
+        }
+    }
+}
+
+/// Validity of the sonar, for telemetry
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SonarStatus {
+    /// the last sample was accepted
+    pub valid: bool,
+    /// time since the last valid sample
+    pub age: f32,
+    pub raw: f32,
+    pub rejected: u32,
+    /// frames with a good and a bad checksum
+    pub good_frames: u32,
+    pub bad_frames: u32,
+}
+
+impl Log for SonarStatus {
+    fn measurements(&self) -> Vec<Measurement> {
+        vec![
+            Measurement {
+                name: "valid",
+                value: self.valid as u8 as f32,
+            },
+            Measurement {
+                name: "age",
+                value: self.age,
+            },
+            Measurement {
+                name: "raw",
+                value: self.raw,
+            },
+            Measurement {
+                name: "rejected",
+                value: self.rejected as f32,
+            },
+            Measurement {
+                name: "good_frames",
+                value: self.good_frames as f32,
+            },
+            Measurement {
+                name: "bad_frames",
+                value: self.bad_frames as f32,
+            },
+        ]
+    }
+}
+
+/// Range gate, Hampel filter and rate limit on the sonar distance
+pub struct SonarFilter {
+    config: SonarFilterConfig,
+    window: VecDeque<f32>,
+    last_valid: Option<f32>,
+    pub status: SonarStatus,
+}
+
+fn median(values: &mut [f32]) -> f32 {
+    values.sort_by(f32::total_cmp);
+    values[values.len() / 2]
+}
+
+impl SonarFilter {
+    pub fn new(config: SonarFilterConfig) -> Self {
+        Self {
+            config,
+            window: VecDeque::with_capacity(config.window),
+            last_valid: None,
+            status: SonarStatus::default(),
+        }
+    }
+
+    /// Takes a raw distance and the time since the previous one. Returns the distance if it is valid
+    pub fn filter(&mut self, distance: f32, dt: f32) -> Option<f32> {
+        self.status.raw = distance;
+        self.status.age += dt;
+
+        let valid =
+            self.in_range(distance) && !self.is_outlier(distance) && self.is_plausible(distance);
+        self.status.valid = valid;
+        if !valid {
+            self.status.rejected += 1;
+            return None;
+        }
+        self.status.age = 0.0;
+        self.last_valid = Some(distance);
+        Some(distance)
+    }
+
+    fn in_range(&self, distance: f32) -> bool {
+        distance >= self.config.min_range && distance <= self.config.max_range
+    }
+
+    /// Hampel identifier on the last samples within range
+    fn is_outlier(&mut self, distance: f32) -> bool {
+        if self.window.len() == self.config.window {
+            self.window.pop_front();
+        }
+        self.window.push_back(distance);
+        if self.window.len() < self.config.window {
+            return false;
+        }
+        let mut values: Vec<f32> = self.window.iter().copied().collect();
+        let m = median(&mut values);
+        let mut deviations: Vec<f32> = values.iter().map(|v| (v - m).abs()).collect();
+        // scaled to the standard deviation of a normal distribution
+        let sigma = 1.4826 * median(&mut deviations);
+        (distance - m).abs() > self.config.outlier_threshold * sigma.max(RESOLUTION)
+    }
+
+    fn is_plausible(&self, distance: f32) -> bool {
+        match self.last_valid {
+            Some(last) => (distance - last).abs() <= self.config.max_rate * self.status.age,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SonarFilter, SonarFilterConfig};
+
+    #[test]
+    fn test_rejection() {
+        let mut filter = SonarFilter::new(SonarFilterConfig::default());
+        let dt = 0.1;
+        for k in 0..10 {
+            let distance = 0.3 + 0.005 * (k % 2) as f32;
+            assert!(filter.filter(distance, dt).is_some());
+        }
+
+        // dropout, spray and an implausible jump
+        assert_eq!(None, filter.filter(0.0, dt));
+        assert_eq!(None, filter.filter(0.9, dt));
+        assert!(!filter.status.valid);
+        assert!((filter.status.age - 0.2).abs() < 1e-5);
+        assert_eq!(2, filter.status.rejected);
+
+        assert_eq!(Some(0.31), filter.filter(0.31, dt));
+        assert_eq!(0.0, filter.status.age);
+    }
+}
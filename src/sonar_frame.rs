@@ -0,0 +1,94 @@
+/// First byte of every frame
+const START: u8 = 0xFF;
+
+/// Parser for the 4 byte frames of A02 style ultrasonic sensors:
+/// 0xFF, distance high byte, distance low byte, checksum.
+/// The checksum is the lowest byte of the sum of the other three.
+/// After a bad frame the parser resynchronises on the next 0xFF, byte by byte
+#[derive(Debug, Default)]
+pub struct FrameParser {
+    frame: [u8; 4],
+    len: usize,
+    pub good_frames: u32,
+    pub bad_frames: u32,
+}
+
+impl FrameParser {
+    /// Feeds one byte. Returns the distance in mm when it completes a valid frame
+    pub fn push(&mut self, byte: u8) -> Option<u16> {
+        if self.len == 0 && byte != START {
+            return None;
+        }
+        self.frame[self.len] = byte;
+        self.len += 1;
+        if self.len < self.frame.len() {
+            return None;
+        }
+
+        let [start, high, low, checksum] = self.frame;
+        if start.wrapping_add(high).wrapping_add(low) == checksum {
+            self.good_frames += 1;
+            self.len = 0;
+            return Some(u16::from_be_bytes([high, low]));
+        }
+
+        // the next frame may already have started within this one
+        self.bad_frames += 1;
+        self.len = 0;
+        for i in 1..self.frame.len() {
+            if self.frame[i] == START {
+                let rest = self.frame.len() - i;
+                self.frame.copy_within(i.., 0);
+                self.len = rest;
+                break;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameParser;
+
+    fn frame(distance_mm: u16) -> [u8; 4] {
+        let [high, low] = distance_mm.to_be_bytes();
+        [0xFF, high, low, 0xFFu8.wrapping_add(high).wrapping_add(low)]
+    }
+
+    fn parse(parser: &mut FrameParser, bytes: &[u8]) -> Vec<u16> {
+        bytes.iter().filter_map(|b| parser.push(*b)).collect()
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut parser = FrameParser::default();
+        // garbage before the first header is skipped
+        let mut bytes = vec![0x12, 0x34];
+        bytes.extend(frame(300));
+        bytes.extend(frame(0x01FF));
+        assert_eq!(vec![300, 0x01FF], parse(&mut parser, &bytes));
+        assert_eq!(2, parser.good_frames);
+        assert_eq!(0, parser.bad_frames);
+    }
+
+    #[test]
+    fn test_resync() {
+        let mut parser = FrameParser::default();
+        // a frame cut off after two bytes, the next one starts within it
+        let mut bytes = vec![0xFF, 0x01];
+        bytes.extend(frame(450));
+        bytes.extend(frame(460));
+        assert_eq!(vec![450, 460], parse(&mut parser, &bytes));
+        assert_eq!(1, parser.bad_frames);
+
+        // a corrupted checksum drops only that frame
+        let mut bad = frame(500);
+        bad[3] ^= 0x01;
+        let mut bytes = bad.to_vec();
+        bytes.extend(frame(510));
+        assert_eq!(vec![510], parse(&mut parser, &bytes));
+        assert_eq!(2, parser.bad_frames);
+        assert_eq!(3, parser.good_frames);
+    }
+}
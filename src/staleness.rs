@@ -0,0 +1,127 @@
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::influx::{Log, Measurement};
+
+/// When each sensor contribution to the measurement was sampled, None before the first sample
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SampleTimes {
+    /// roll and pitch
+    pub attitude: Option<Instant>,
+    /// pitch and yaw rate
+    pub rates: Option<Instant>,
+    pub altitude: Option<Instant>,
+    /// vertical acceleration
+    pub acceleration: Option<Instant>,
+}
+
+impl SampleTimes {
+    pub fn ages(&self, now: Instant) -> SignalAges {
+        let age = |time: Option<Instant>| time.map(|t| now.saturating_duration_since(t));
+        SignalAges {
+            attitude: age(self.attitude),
+            rates: age(self.rates),
+            altitude: age(self.altitude),
+            acceleration: age(self.acceleration),
+        }
+    }
+}
+
+/// Age of each signal, None if it was never sampled
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SignalAges {
+    pub attitude: Option<Duration>,
+    pub rates: Option<Duration>,
+    pub altitude: Option<Duration>,
+    pub acceleration: Option<Duration>,
+}
+
+impl Log for SignalAges {
+    fn measurements(&self) -> Vec<Measurement> {
+        // -1 if never sampled
+        let seconds = |age: Option<Duration>| age.map_or(-1.0, |age| age.as_secs_f32());
+        vec![
+            Measurement {
+                name: "attitude",
+                value: seconds(self.attitude),
+            },
+            Measurement {
+                name: "rates",
+                value: seconds(self.rates),
+            },
+            Measurement {
+                name: "altitude",
+                value: seconds(self.altitude),
+            },
+            Measurement {
+                name: "acceleration",
+                value: seconds(self.acceleration),
+            },
+        ]
+    }
+}
+
+/// Oldest acceptable sample of each signal in ms, the controller is disengaged beyond. 0 disables the check
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct MaxAge {
+    pub attitude: u64,
+    pub rates: u64,
+    pub altitude: u64,
+    pub acceleration: u64,
+}
+
+impl Default for MaxAge {
+    fn default() -> Self {
+        Self {
+            attitude: 100,
+            rates: 100,
+            altitude: 2000,
+            acceleration: 100,
+        }
+    }
+}
+
+fn fresh(age: Option<Duration>, max: u64) -> bool {
+    max == 0 || age.is_some_and(|age| age.as_millis() <= max as u128)
+}
+
+impl MaxAge {
+    /// True if roll, pitch and the rates have been sampled recently enough to stabilise the attitude
+    pub fn attitude_fresh(&self, ages: &SignalAges) -> bool {
+        fresh(ages.attitude, self.attitude) && fresh(ages.rates, self.rates)
+    }
+
+    /// True if the inputs of the altitude estimate have been sampled recently enough
+    pub fn altitude_fresh(&self, ages: &SignalAges) -> bool {
+        fresh(ages.altitude, self.altitude) && fresh(ages.acceleration, self.acceleration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MaxAge, SampleTimes};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_max_age() {
+        let now = Instant::now();
+        let mut sampled = SampleTimes {
+            attitude: Some(now - Duration::from_millis(10)),
+            rates: Some(now - Duration::from_millis(10)),
+            altitude: Some(now - Duration::from_millis(500)),
+            acceleration: None,
+        };
+        let max_age = MaxAge::default();
+        assert!(max_age.attitude_fresh(&sampled.ages(now)));
+        // never sampled
+        assert!(!max_age.altitude_fresh(&sampled.ages(now)));
+
+        sampled.acceleration = Some(now);
+        assert!(max_age.altitude_fresh(&sampled.ages(now)));
+        let later = sampled.ages(now + Duration::from_millis(200));
+        assert!(!max_age.attitude_fresh(&later));
+        assert!(!max_age.altitude_fresh(&later));
+    }
+}